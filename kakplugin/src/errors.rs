@@ -21,6 +21,8 @@ pub enum KakError {
     Custom(String),
     /// Custom static error string
     CustomStatic(&'static str),
+    /// Custom error string, with more information in the details
+    CustomDetails(String, String),
     /// The selections/selections_desc list passed was empty
     SetEmptySelections,
     /// The register register has no content
@@ -41,6 +43,7 @@ impl KakError {
             Self::NotImplemented(e) => e.to_string(),
             Self::Custom(s) => s.clone(),
             Self::CustomStatic(s) => s.to_string(),
+            Self::CustomDetails(_, d) => d.clone(),
             Self::SetEmptySelections => {
                 String::from("Attempted to set selections/selections_desc to empty list")
             }
@@ -64,6 +67,7 @@ impl Display for KakError {
             Self::NotImplemented(_) => write!(f, "Not Implemented"),
             Self::Custom(s) => write!(f, "{}", s),
             Self::CustomStatic(s) => write!(f, "{}", s),
+            Self::CustomDetails(s, _) => write!(f, "{}", s),
             Self::SetEmptySelections => write!(
                 f,
                 "Attempted to set selections/selections_desc to empty list"
@@ -239,6 +239,15 @@ pub fn display_message<S: AsRef<str> + Clone + Display>(
     Ok(())
 }
 
+/// Writes a message to the `*debug*` buffer
+///
+/// # Errors
+///
+/// Will return `Err` if command fifo could not be opened or written to
+pub fn echo_debug<S: AsRef<str>>(message: S) -> Result<(), KakError> {
+    cmd(format!("echo -debug '{}'", escape(message.as_ref())))
+}
+
 /// Escapes a string to be sent to kak by replacing single tick with two single tics
 ///
 /// # Examples
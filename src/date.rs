@@ -0,0 +1,569 @@
+use chrono::{DateTime, Duration, FixedOffset, Months, NaiveDate, NaiveDateTime, NaiveTime};
+use kakplugin::KakError;
+use std::{fmt, ops::Range, str::FromStr};
+
+/// Formats recognized in selections, tried in order at each word boundary
+///
+/// The matched format is used to write the result, so the original format is kept
+const FORMATS: [(&str, Kind); 16] = [
+    ("%Y-%m-%dT%H:%M:%S%.fZ", Kind::DateTime),
+    ("%Y-%m-%dT%H:%M:%S%.f%:z", Kind::Zoned),
+    ("%Y-%m-%d %H:%M:%S%.f%:z", Kind::Zoned),
+    ("%Y-%m-%dT%H:%M:%S%.f", Kind::DateTime),
+    ("%Y-%m-%d %H:%M:%S%.f", Kind::DateTime),
+    ("%Y-%m-%dT%H:%M", Kind::DateTime),
+    ("%Y-%m-%d %H:%M", Kind::DateTime),
+    ("%Y-%m-%d", Kind::Date),
+    ("%Y/%m/%d", Kind::Date),
+    ("%d.%m.%Y", Kind::Date),
+    ("%m/%d/%Y", Kind::Date),
+    ("%d %b %Y", Kind::Date),
+    ("%b %d, %Y", Kind::Date),
+    ("%H:%M:%S%.f", Kind::Time),
+    ("%H:%M", Kind::Time),
+    // Keep this last so it does not shadow the formats above
+    (COMPACT_FORMAT, Kind::Date),
+];
+
+/// A date written as one number, which is only treated as a date when there is no other way to read it
+const COMPACT_FORMAT: &str = "%Y%m%d";
+
+/// Separators inside dates, versions, and times, so a date cannot start or end next to one followed by a digit
+const TOKEN_SEPARATORS: [char; 3] = ['.', '/', ':'];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Zoned,
+    DateTime,
+    Date,
+    Time,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DateValue {
+    Zoned(DateTime<FixedOffset>),
+    DateTime(NaiveDateTime),
+    Date(NaiveDate),
+    Time(NaiveTime),
+}
+
+impl Kind {
+    /// Parses the start of `s`, returning the value and the unparsed remainder
+    fn parse<'a>(self, s: &'a str, format: &str) -> Option<(DateValue, &'a str)> {
+        match self {
+            Self::Zoned => DateTime::parse_and_remainder(s, format)
+                .ok()
+                .map(|(v, r)| (DateValue::Zoned(v), r)),
+            Self::DateTime => NaiveDateTime::parse_and_remainder(s, format)
+                .ok()
+                .map(|(v, r)| (DateValue::DateTime(v), r)),
+            Self::Date => NaiveDate::parse_and_remainder(s, format)
+                .ok()
+                .map(|(v, r)| (DateValue::Date(v), r)),
+            Self::Time => NaiveTime::parse_and_remainder(s, format)
+                .ok()
+                .map(|(v, r)| (DateValue::Time(v), r)),
+        }
+    }
+}
+
+impl DateValue {
+    /// Adds `span`, returning `None` on overflow or if `span` does not apply (like adding hours to a date)
+    fn add(self, span: Span) -> Option<Self> {
+        if let Some(months) = span.months() {
+            let months = i32::try_from(months).ok()?;
+            let add_months = |months: i32| Months::new(months.unsigned_abs());
+
+            return match self {
+                Self::Zoned(d) if months < 0 => {
+                    d.checked_sub_months(add_months(months)).map(Self::Zoned)
+                }
+                Self::Zoned(d) => d.checked_add_months(add_months(months)).map(Self::Zoned),
+                Self::DateTime(d) if months < 0 => {
+                    d.checked_sub_months(add_months(months)).map(Self::DateTime)
+                }
+                Self::DateTime(d) => d.checked_add_months(add_months(months)).map(Self::DateTime),
+                Self::Date(d) if months < 0 => {
+                    d.checked_sub_months(add_months(months)).map(Self::Date)
+                }
+                Self::Date(d) => d.checked_add_months(add_months(months)).map(Self::Date),
+                Self::Time(_) => None,
+            };
+        }
+
+        let duration = Duration::try_seconds(span.seconds()?)?;
+        match self {
+            Self::Zoned(d) => d.checked_add_signed(duration).map(Self::Zoned),
+            Self::DateTime(d) => d.checked_add_signed(duration).map(Self::DateTime),
+            // Dates can only move by whole days
+            Self::Date(d) if duration.num_seconds() % 86400 == 0 => {
+                d.checked_add_signed(duration).map(Self::Date)
+            }
+            Self::Date(_) => None,
+            // Times wrap around midnight
+            Self::Time(t) => Some(Self::Time(t.overflowing_add_signed(duration).0)),
+        }
+    }
+
+    /// Adds `span` to a value parsed with `format`
+    ///
+    /// Returns `None` if the result cannot be written in `format`, like adding seconds to `12:00`
+    fn add_in_format(self, span: Span, format: &str) -> Option<Self> {
+        let precision = if format.contains("%S") {
+            1
+        } else if format.contains("%M") {
+            60
+        } else {
+            86400
+        };
+
+        if matches!(span.seconds(), Some(seconds) if seconds % precision != 0) {
+            return None;
+        }
+
+        self.add(span)
+    }
+
+    /// Gets the time from `other` to `self`, if both are the same kind of value
+    fn since(self, other: Self) -> Option<Duration> {
+        match (self, other) {
+            (Self::Zoned(a), Self::Zoned(b)) => Some(a.signed_duration_since(b)),
+            (Self::DateTime(a), Self::DateTime(b)) => Some(a.signed_duration_since(b)),
+            (Self::Date(a), Self::Date(b)) => Some(a.signed_duration_since(b)),
+            (Self::Time(a), Self::Time(b)) => Some(a.signed_duration_since(b)),
+            _ => None,
+        }
+    }
+
+    fn format(self, format: &str) -> String {
+        match self {
+            Self::Zoned(d) => d.format(format).to_string(),
+            Self::DateTime(d) => d.format(format).to_string(),
+            Self::Date(d) => d.format(format).to_string(),
+            Self::Time(t) => t.format(format).to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unit {
+    Second,
+    Minute,
+    Hour,
+    Day,
+    Week,
+    Month,
+    Year,
+}
+
+/// An amount of time, like `3d` or `-2h`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub count: i64,
+    pub unit: Unit,
+}
+
+impl Span {
+    #[must_use]
+    pub const fn negate(self) -> Self {
+        Self {
+            count: self.count.saturating_neg(),
+            unit: self.unit,
+        }
+    }
+
+    /// Number of months, if this span is measured in months or years
+    const fn months(self) -> Option<i64> {
+        match self.unit {
+            Unit::Month => Some(self.count),
+            Unit::Year => self.count.checked_mul(12),
+            _ => None,
+        }
+    }
+
+    /// Number of seconds, if this span has a fixed length
+    const fn seconds(self) -> Option<i64> {
+        let unit_seconds = match self.unit {
+            Unit::Second => 1,
+            Unit::Minute => 60,
+            Unit::Hour => 3600,
+            Unit::Day => 86400,
+            Unit::Week => 604_800,
+            Unit::Month | Unit::Year => return None,
+        };
+        self.count.checked_mul(unit_seconds)
+    }
+}
+
+impl FromStr for Span {
+    type Err = KakError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (count, unit) = s
+            .find(|c: char| c.is_ascii_alphabetic())
+            .map(|i| s.split_at(i))
+            .ok_or_else(|| KakError::Custom(format!("Time span '{s}' has no unit")))?;
+
+        Ok(Self {
+            count: count
+                .parse()
+                .map_err(|e| KakError::Custom(format!("Time span '{s}' is invalid: {e}")))?,
+            unit: match unit {
+                "s" | "sec" | "secs" | "second" | "seconds" => Unit::Second,
+                "m" | "min" | "mins" | "minute" | "minutes" => Unit::Minute,
+                "h" | "hr" | "hrs" | "hour" | "hours" => Unit::Hour,
+                "d" | "day" | "days" => Unit::Day,
+                "w" | "week" | "weeks" => Unit::Week,
+                "mo" | "month" | "months" => Unit::Month,
+                "y" | "yr" | "yrs" | "year" | "years" => Unit::Year,
+                _ => {
+                    return Err(KakError::Custom(format!(
+                        "Time span '{s}' has an unknown unit '{unit}'"
+                    )))
+                }
+            },
+        })
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unit = match self.unit {
+            Unit::Second => "s",
+            Unit::Minute => "m",
+            Unit::Hour => "h",
+            Unit::Day => "d",
+            Unit::Week => "w",
+            Unit::Month => "mo",
+            Unit::Year => "y",
+        };
+        write!(f, "{}{unit}", self.count)
+    }
+}
+
+/// A date or time found in a string
+struct DateMatch {
+    /// Position of the date in the string
+    range: Range<usize>,
+    value: DateValue,
+    /// Format the date was parsed with
+    format: &'static str,
+}
+
+/// Finds the first date or time in `s` that is a whole token
+///
+/// A token is not next to a letter or digit, or to a separator like `.` that is next to a digit, so `1.2.3` is not a date
+fn find_date(s: &str) -> Option<DateMatch> {
+    let mut prev: [Option<char>; 2] = [None, None];
+
+    for (start, c) in s.char_indices() {
+        let at_boundary = match prev {
+            [_, Some(p)] if p.is_alphanumeric() => false,
+            [Some(d), Some(p)] => !(TOKEN_SEPARATORS.contains(&p) && d.is_ascii_digit()),
+            _ => true,
+        };
+        prev = [prev[1], Some(c)];
+
+        if !at_boundary || !c.is_ascii_alphanumeric() {
+            continue;
+        }
+
+        for (format, kind) in FORMATS {
+            let Some((value, remainder)) = kind.parse(&s[start..], format) else {
+                continue;
+            };
+
+            let mut after = remainder.chars();
+            let at_end = match (after.next(), after.next()) {
+                (Some(c), _) if c.is_alphanumeric() => false,
+                (Some(c), Some(d)) => !(TOKEN_SEPARATORS.contains(&c) && d.is_ascii_digit()),
+                _ => true,
+            };
+
+            let range = start..s.len() - remainder.len();
+            if !at_end || !has_shape(&s[range.clone()], format) {
+                continue;
+            }
+
+            return Some(DateMatch {
+                range,
+                value,
+                format,
+            });
+        }
+    }
+
+    None
+}
+
+/// Checks that `text` has exactly as many digits as each field in `format`
+///
+/// chrono also accepts short fields, like a 1 digit year, which would make numbers like `6/3/2` into dates
+fn has_shape(mut text: &str, mut format: &str) -> bool {
+    while !format.is_empty() {
+        let (rest_format, rest_text) = match_field(text, format);
+
+        match rest_text {
+            Some(t) => text = t,
+            None => return false,
+        }
+        format = rest_format;
+    }
+
+    text.is_empty()
+}
+
+/// Matches the first field or literal of `format` against `text`, returning the rest of both
+fn match_field<'t, 'f>(text: &'t str, format: &'f str) -> (&'f str, Option<&'t str>) {
+    fn digits(text: &str, n: usize) -> Option<&str> {
+        text.get(..n)
+            .filter(|d| d.bytes().all(|b| b.is_ascii_digit()))
+            .map(|_| &text[n..])
+    }
+
+    if let Some(f) = format.strip_prefix("%Y") {
+        return (f, digits(text, 4));
+    }
+
+    if let Some(f) = ["%m", "%d", "%H", "%M", "%S"]
+        .iter()
+        .find_map(|spec| format.strip_prefix(spec))
+    {
+        return (f, digits(text, 2));
+    }
+
+    if let Some(f) = format.strip_prefix("%b") {
+        // Month names like `Jan` or `January`
+        let rest = text.trim_start_matches(|c: char| c.is_ascii_alphabetic());
+        return (f, (text.len() - rest.len() >= 3).then_some(rest));
+    }
+
+    if let Some(f) = format.strip_prefix("%.f") {
+        let rest = text
+            .strip_prefix('.')
+            .map_or(text, |t| t.trim_start_matches(|c: char| c.is_ascii_digit()));
+        return (f, Some(rest));
+    }
+
+    if let Some(f) = format.strip_prefix("%:z") {
+        let rest = text
+            .strip_prefix(['+', '-'])
+            .and_then(|t| digits(t, 2))
+            .and_then(|t| t.strip_prefix(':'))
+            .and_then(|t| digits(t, 2));
+        return (f, rest);
+    }
+
+    let c = format.chars().next().unwrap_or_default();
+    (&format[c.len_utf8()..], text.strip_prefix(c))
+}
+
+/// Adds `span` to the first date or time in `s`, keeping its format
+///
+/// Returns `None` if there is no date in `s`, or if `span` cannot be added to it
+///
+/// # Examples
+///
+/// ```
+/// assert_eq!(add_to_date("due: 2023-01-31", "1mo".parse()?).as_deref(), Some("due: 2023-02-28"));
+/// assert_eq!(add_to_date("23:30", "2h".parse()?).as_deref(), Some("01:30"));
+/// ```
+pub fn add_to_date(s: &str, span: Span) -> Option<String> {
+    let m = find_date(s)?;
+    let new_value = m.value.add_in_format(span, m.format)?;
+
+    Some(format!(
+        "{}{}{}",
+        &s[..m.range.start],
+        new_value.format(m.format),
+        &s[m.range.end..]
+    ))
+}
+
+/// Evaluates date arithmetic, like `2023-01-01 + 3d - 2h` or `2023-03-01 - 2023-01-01`
+///
+/// Returns `None` if `expr` is not date arithmetic, so it can be evaluated some other way
+///
+/// # Examples
+///
+/// ```
+/// assert_eq!(eval("2023-01-01 + 1w + 1d").as_deref(), Some("2023-01-09"));
+/// assert_eq!(eval("2023-03-01 - 2023-01-01").as_deref(), Some("59d"));
+/// assert_eq!(eval("1 + 2"), None);
+/// ```
+pub fn eval(expr: &str) -> Option<String> {
+    let expr = expr.trim();
+    let first = find_date(expr).filter(|m| m.range.start == 0)?;
+
+    let mut value = first.value;
+    let mut rest = &expr[first.range.end..];
+    let mut applied_span = false;
+
+    loop {
+        rest = rest.trim_start();
+        let Some(c) = rest.chars().next() else {
+            break;
+        };
+        let negative = match c {
+            '+' => false,
+            '-' => true,
+            _ => return None,
+        };
+        let operand = rest[1..].trim_start();
+
+        // Subtracting two dates gives the time between them
+        if let Some(other) = find_date(operand).filter(|m| m.range.start == 0) {
+            // `20230105 - 20230101` is more likely to be plain subtraction
+            if !negative
+                || applied_span
+                || !operand[other.range.end..].trim().is_empty()
+                || first.format == COMPACT_FORMAT
+                || other.format == COMPACT_FORMAT
+            {
+                return None;
+            }
+
+            return value.since(other.value).map(format_duration);
+        }
+
+        let end = operand
+            .find(|c: char| !c.is_ascii_alphanumeric())
+            .unwrap_or(operand.len());
+        let span = operand[..end].parse::<Span>().ok()?;
+        value = value.add_in_format(if negative { span.negate() } else { span }, first.format)?;
+        applied_span = true;
+        rest = &operand[end..];
+    }
+
+    applied_span.then(|| value.format(first.format))
+}
+
+/// Formats a duration using the same units as `Span`, like `1d 2h 30m`
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.num_seconds();
+    let abs = seconds.unsigned_abs();
+
+    let parts = [
+        (abs / 86400, "d"),
+        (abs % 86400 / 3600, "h"),
+        (abs % 3600 / 60, "m"),
+        (abs % 60, "s"),
+    ]
+    .iter()
+    .filter(|(n, _)| *n != 0)
+    .map(|(n, unit)| format!("{n}{unit}"))
+    .collect::<Vec<_>>();
+
+    if parts.is_empty() {
+        String::from("0s")
+    } else {
+        format!("{}{}", if seconds < 0 { "-" } else { "" }, parts.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(s: &str) -> Span {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_add_to_date() {
+        assert_eq!(
+            add_to_date("2023-01-05", span("3d")).as_deref(),
+            Some("2023-01-08")
+        );
+        assert_eq!(
+            add_to_date("due: 2023-01-31!", span("1mo")).as_deref(),
+            Some("due: 2023-02-28!")
+        );
+        assert_eq!(
+            add_to_date("2023-01-05T10:00:00Z", span("-2h")).as_deref(),
+            Some("2023-01-05T08:00:00Z")
+        );
+        assert_eq!(
+            add_to_date("2023-01-05T23:30:00.250+02:00", span("1h")).as_deref(),
+            Some("2023-01-06T00:30:00.250+02:00")
+        );
+        assert_eq!(
+            add_to_date("2023-01-05 10:00", span("90m")).as_deref(),
+            Some("2023-01-05 11:30")
+        );
+        assert_eq!(
+            add_to_date("31.12.2023", span("1d")).as_deref(),
+            Some("01.01.2024")
+        );
+        assert_eq!(
+            add_to_date("12/31/2023", span("1y")).as_deref(),
+            Some("12/31/2024")
+        );
+        assert_eq!(
+            add_to_date("05 Jan 2023", span("1w")).as_deref(),
+            Some("12 Jan 2023")
+        );
+        assert_eq!(
+            add_to_date("Jan 05, 2023", span("1d")).as_deref(),
+            Some("Jan 06, 2023")
+        );
+        assert_eq!(add_to_date("23:30", span("2h")).as_deref(), Some("01:30"));
+        assert_eq!(
+            add_to_date("20230105", span("1d")).as_deref(),
+            Some("20230106")
+        );
+        // Hours cannot be added to a date, and there is no date in a plain word
+        assert_eq!(add_to_date("2023-01-05", span("2h")), None);
+        assert_eq!(add_to_date("version1", span("1d")), None);
+        // Version strings and short numbers are not dates
+        assert_eq!(add_to_date("1.2.3", span("1d")), None);
+        assert_eq!(add_to_date("v10.12.2023.1", span("1d")), None);
+        assert_eq!(add_to_date("8/4/2", span("1d")), None);
+        // The result would need seconds, which the format does not have
+        assert_eq!(add_to_date("12:00", span("30s")), None);
+        assert_eq!(add_to_date("12:00", span("120s")).as_deref(), Some("12:02"));
+    }
+
+    #[test]
+    fn test_eval() {
+        assert_eq!(eval("2023-01-01 + 1w + 1d").as_deref(), Some("2023-01-09"));
+        assert_eq!(
+            eval(" 2023-01-01T00:00 - 30m ").as_deref(),
+            Some("2022-12-31T23:30")
+        );
+        assert_eq!(eval("2023-03-01 - 2023-01-01").as_deref(), Some("59d"));
+        assert_eq!(
+            eval("2023-01-02 10:00 - 2023-01-01 07:29").as_deref(),
+            Some("1d 2h 31m")
+        );
+        assert_eq!(eval("10:00 - 12:00").as_deref(), Some("-2h"));
+        assert_eq!(eval("2023-01-01"), None);
+        assert_eq!(eval("2023-01-01 + 2023-01-01"), None);
+        assert_eq!(eval("1 + 2"), None);
+
+        // Plain arithmetic and version strings are not dates
+        assert_eq!(eval("6/3/2 - 4/2/1"), None);
+        assert_eq!(eval("1-2-3 - 1-1-3"), None);
+        assert_eq!(eval("10.1.2 - 1.1.1"), None);
+        assert_eq!(eval("8/4/2 + 1d"), None);
+        assert_eq!(eval("20230105 - 20230101"), None);
+        assert_eq!(eval("20230105 + 1d").as_deref(), Some("20230106"));
+        assert_eq!(eval("12:00 - 30s"), None);
+        assert_eq!(eval("12:00:00 - 30s").as_deref(), Some("11:59:30"));
+        assert_eq!(eval("2023-01-01-1d").as_deref(), Some("2022-12-31"));
+    }
+
+    #[test]
+    fn test_span() {
+        assert_eq!(
+            span("-3d"),
+            Span {
+                count: -3,
+                unit: Unit::Day
+            }
+        );
+        assert_eq!(span("2months").to_string(), "2mo");
+        assert!("3".parse::<Span>().is_err());
+        assert!("3x".parse::<Span>().is_err());
+    }
+}
@@ -0,0 +1,307 @@
+use kakplugin::{get_selections_with_desc, set_selections, KakError};
+use std::borrow::Cow;
+
+#[derive(clap::Args, Debug)]
+pub struct Options {
+    #[clap(
+        index = 1,
+        allow_negative_numbers = true,
+        help = "First number of the sequence (default: 1, or 0 with --add)"
+    )]
+    start: Option<i64>,
+    #[clap(
+        short,
+        long,
+        default_value = "1",
+        allow_negative_numbers = true,
+        help = "Amount to add for each selection"
+    )]
+    step: i64,
+    #[clap(
+        short,
+        long,
+        value_enum,
+        default_value = "decimal",
+        help = "Format of each number"
+    )]
+    format: Format,
+    #[clap(short, long, help = "Pad each number to at least this width")]
+    width: Option<usize>,
+    #[clap(
+        short,
+        long,
+        requires = "width",
+        help = "Pad with zeros instead of spaces"
+    )]
+    zero_pad: bool,
+    #[clap(
+        short,
+        long,
+        help = "Add the sequence to the number in each selection instead of replacing it"
+    )]
+    add: bool,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    /// `1, 2, 3`
+    Decimal,
+    /// `9, a, b`
+    Hex,
+    /// `9, A, B`
+    UpperHex,
+    /// `7, 10, 11`
+    Octal,
+    /// `1, 10, 11`
+    Binary,
+    /// `i, ii, iii`
+    Roman,
+    /// `I, II, III`
+    UpperRoman,
+    /// `a, b, ..., z, aa`
+    Letters,
+    /// `A, B, ..., Z, AA`
+    UpperLetters,
+}
+
+impl Format {
+    /// Formats `n`, returning `None` if `n` cannot be represented (for example, roman numerals below 1)
+    fn format(self, n: i64) -> Option<String> {
+        let sign = if n < 0 { "-" } else { "" };
+        let abs = n.unsigned_abs();
+
+        Some(match self {
+            Self::Decimal => n.to_string(),
+            Self::Hex => format!("{sign}{abs:x}"),
+            Self::UpperHex => format!("{sign}{abs:X}"),
+            Self::Octal => format!("{sign}{abs:o}"),
+            Self::Binary => format!("{sign}{abs:b}"),
+            Self::Roman => to_roman(n)?.to_lowercase(),
+            Self::UpperRoman => to_roman(n)?,
+            Self::Letters => to_letters(n)?,
+            Self::UpperLetters => to_letters(n)?.to_uppercase(),
+        })
+    }
+
+    /// Parses a number written in this format, returning `None` if it is not valid
+    fn parse(self, s: &str) -> Option<i64> {
+        let radix = match self {
+            Self::Decimal => 10,
+            Self::Hex | Self::UpperHex => 16,
+            Self::Octal => 8,
+            Self::Binary => 2,
+            Self::Roman | Self::UpperRoman => return from_roman(s),
+            Self::Letters | Self::UpperLetters => return from_letters(s),
+        };
+
+        let (negative, digits) = s
+            .strip_prefix('-')
+            .map_or_else(|| (false, s.strip_prefix('+').unwrap_or(s)), |d| (true, d));
+        let digits = match radix {
+            16 => digits
+                .strip_prefix("0x")
+                .or_else(|| digits.strip_prefix("0X"))
+                .unwrap_or(digits),
+            8 => digits.strip_prefix("0o").unwrap_or(digits),
+            2 => digits.strip_prefix("0b").unwrap_or(digits),
+            _ => digits,
+        };
+
+        // from_str_radix accepts its own sign, which would allow "--1"
+        if digits.starts_with(['+', '-']) {
+            return None;
+        }
+
+        let n = i64::from_str_radix(digits, radix).ok()?;
+        Some(if negative { -n } else { n })
+    }
+}
+
+const ROMAN_NUMERALS: [(i64, &str); 13] = [
+    (1000, "M"),
+    (900, "CM"),
+    (500, "D"),
+    (400, "CD"),
+    (100, "C"),
+    (90, "XC"),
+    (50, "L"),
+    (40, "XL"),
+    (10, "X"),
+    (9, "IX"),
+    (5, "V"),
+    (4, "IV"),
+    (1, "I"),
+];
+
+/// Converts `n` to uppercase roman numerals. Only `1..=3999` can be represented
+fn to_roman(mut n: i64) -> Option<String> {
+    if !(1..=3999).contains(&n) {
+        return None;
+    }
+
+    let mut ret = String::new();
+    for (value, numeral) in ROMAN_NUMERALS {
+        while n >= value {
+            ret.push_str(numeral);
+            n -= value;
+        }
+    }
+    Some(ret)
+}
+
+/// Parses roman numerals in either case, only accepting the canonical form of each number
+fn from_roman(s: &str) -> Option<i64> {
+    let upper = s.to_uppercase();
+    let mut remaining = upper.as_str();
+    let mut ret = 0;
+
+    for (value, numeral) in ROMAN_NUMERALS {
+        while let Some(r) = remaining.strip_prefix(numeral) {
+            remaining = r;
+            ret += value;
+        }
+    }
+
+    // Round tripping rejects things like "IIII" or "IM"
+    (remaining.is_empty() && to_roman(ret).as_deref() == Some(upper.as_str())).then_some(ret)
+}
+
+/// Converts `n` to lowercase bijective base-26 letters (`1 => a`, `26 => z`, `27 => aa`)
+fn to_letters(n: i64) -> Option<String> {
+    if n < 1 {
+        return None;
+    }
+
+    let mut n = n.unsigned_abs();
+    let mut ret = Vec::new();
+    while n > 0 {
+        n -= 1;
+        ret.push(b'a' + (n % 26) as u8);
+        n /= 26;
+    }
+    ret.reverse();
+    String::from_utf8(ret).ok()
+}
+
+/// Parses bijective base-26 letters in either case
+fn from_letters(s: &str) -> Option<i64> {
+    if s.is_empty() {
+        return None;
+    }
+
+    s.chars().try_fold(0_i64, |acc, c| {
+        let digit = match c {
+            'a'..='z' => c as i64 - 'a' as i64 + 1,
+            'A'..='Z' => c as i64 - 'A' as i64 + 1,
+            _ => return None,
+        };
+        acc.checked_mul(26)?.checked_add(digit)
+    })
+}
+
+/// Pads `s` to `width`, inserting zeros after the sign if `zero_pad` is set
+fn pad(s: &str, width: usize, zero_pad: bool) -> String {
+    if zero_pad {
+        let (sign, digits) = s.strip_prefix('-').map_or(("", s), |d| ("-", d));
+        format!(
+            "{sign}{digits:0>width$}",
+            width = width.saturating_sub(sign.len())
+        )
+    } else {
+        format!("{s:>width$}")
+    }
+}
+
+pub fn enumerate(options: &Options) -> Result<String, KakError> {
+    let start = options.start.unwrap_or_else(|| i64::from(!options.add));
+    let mut err_count: usize = 0;
+
+    // Selections are numbered in document order, regardless of which one is primary
+    let selections = get_selections_with_desc(None)?;
+
+    set_selections(selections.iter().enumerate().map(|(i, s)| {
+        let new_value = (|| {
+            let offset = i64::try_from(i).ok()?.checked_mul(options.step)?;
+            let mut value = start.checked_add(offset)?;
+
+            if options.add {
+                value = value.checked_add(options.format.parse(s.content.trim())?)?;
+            }
+
+            let formatted = options.format.format(value)?;
+            Some(match options.width {
+                Some(width) => pad(&formatted, width, options.zero_pad),
+                None => formatted,
+            })
+        })();
+
+        new_value.map_or_else(
+            || {
+                // Leave the selection alone if the number could not be parsed or represented
+                err_count = err_count.saturating_add(1);
+                Cow::Borrowed(s.content.as_str())
+            },
+            Cow::Owned,
+        )
+    }))?;
+
+    Ok(if err_count == 0 {
+        format!("Enumerated {} selections", selections.len())
+    } else {
+        format!(
+            "Enumerated {} selections ({} error{})",
+            selections.len().saturating_sub(err_count),
+            err_count,
+            if err_count == 1 { "" } else { "s" }
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        assert_eq!(Format::Decimal.format(-12).as_deref(), Some("-12"));
+        assert_eq!(Format::Hex.format(255).as_deref(), Some("ff"));
+        assert_eq!(Format::UpperHex.format(-255).as_deref(), Some("-FF"));
+        assert_eq!(Format::Octal.format(8).as_deref(), Some("10"));
+        assert_eq!(Format::Binary.format(5).as_deref(), Some("101"));
+        assert_eq!(Format::Roman.format(1994).as_deref(), Some("mcmxciv"));
+        assert_eq!(Format::UpperRoman.format(4).as_deref(), Some("IV"));
+        assert_eq!(Format::Roman.format(0), None);
+        assert_eq!(Format::Letters.format(1).as_deref(), Some("a"));
+        assert_eq!(Format::Letters.format(26).as_deref(), Some("z"));
+        assert_eq!(Format::UpperLetters.format(27).as_deref(), Some("AA"));
+        assert_eq!(Format::Letters.format(702).as_deref(), Some("zz"));
+        assert_eq!(Format::Letters.format(0), None);
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Format::Decimal.parse("+12"), Some(12));
+        assert_eq!(Format::Decimal.parse("--12"), None);
+        assert_eq!(Format::Hex.parse("0xff"), Some(255));
+        assert_eq!(Format::UpperHex.parse("-FF"), Some(-255));
+        assert_eq!(Format::Binary.parse("0b101"), Some(5));
+        assert_eq!(Format::Roman.parse("MCMXCIV"), Some(1994));
+        assert_eq!(Format::Roman.parse("iiii"), None);
+        assert_eq!(Format::Letters.parse("AA"), Some(27));
+        assert_eq!(Format::Letters.parse("a1"), None);
+
+        for n in 1..1000 {
+            for format in [Format::Hex, Format::Roman, Format::Letters] {
+                assert_eq!(format.parse(&format.format(n).unwrap()), Some(n));
+            }
+        }
+    }
+
+    #[test]
+    fn test_pad() {
+        assert_eq!(pad("7", 3, true), "007");
+        assert_eq!(pad("-7", 3, true), "-07");
+        assert_eq!(pad("7", 3, false), "  7");
+        assert_eq!(pad("1234", 3, true), "1234");
+    }
+}
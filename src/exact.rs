@@ -0,0 +1,517 @@
+//! Exact arithmetic for `math-eval --exact`
+//!
+//! Expressions are parsed into big rationals, so large integers never overflow and decimals like `0.1` are not rounded to binary fractions.
+//! Only arithmetic is supported (`+ - * / % ^`, parentheses, a few functions, and variables), since everything else in evalexpr relies on i64 and f64
+use num_bigint::BigInt;
+use num_integer::Integer;
+use num_rational::BigRational;
+use num_traits::{One, Signed, ToPrimitive, Zero};
+use std::{collections::HashMap, fmt};
+
+/// Largest power of ten allowed in a number like `1e10`
+const MAX_EXPONENT: i32 = 10_000;
+
+/// Largest result of `^` in bits (about 300,000 digits), so a typo cannot make the result take forever to compute
+const MAX_POWER_BITS: u64 = 1 << 20;
+
+/// Variables shared between every selection, plus the results of each selection evaluated so far
+///
+/// Supports the same built-in variables as the evalexpr context (`i`, `prev`, and `s[N]`)
+#[derive(Debug, Default)]
+pub struct ExactContext {
+    variables: HashMap<String, BigRational>,
+    results: Vec<Option<BigRational>>,
+}
+
+impl ExactContext {
+    /// Evaluates the next selection, recording its result for `prev` and `s[N]`
+    ///
+    /// Returns `Ok(None)` if the selection only assigns variables
+    pub fn evaluate_next(&mut self, s: &str) -> Result<Option<BigRational>, String> {
+        let result = self.evaluate(s);
+        self.results
+            .push(result.as_ref().ok().and_then(Option::as_ref).cloned());
+        result
+    }
+
+    /// Evaluates every `;` separated statement in `s`, returning the value of the last one
+    fn evaluate(&mut self, s: &str) -> Result<Option<BigRational>, String> {
+        let tokens = tokenize(s)?;
+        let mut ret = None;
+
+        for statement in tokens.split(|t| *t == Token::Op(';')) {
+            ret = match statement {
+                [] => None,
+                [Token::Ident(name), Token::Op('='), expr @ ..] => {
+                    if matches!(name.as_str(), "i" | "prev" | "s") {
+                        return Err(format!(
+                            "'{name}' is a built-in variable and cannot be assigned"
+                        ));
+                    }
+                    let value = Parser::new(expr, self).parse()?;
+                    self.variables.insert(name.clone(), value);
+                    None
+                }
+                expr => Some(Parser::new(expr, self).parse()?),
+            };
+        }
+
+        Ok(ret)
+    }
+
+    fn variable(&self, name: &str) -> Result<BigRational, String> {
+        match name {
+            "i" => Ok(BigRational::from_integer(BigInt::from(
+                self.results.len() + 1,
+            ))),
+            "prev" => self
+                .results
+                .iter()
+                .rev()
+                .find_map(Clone::clone)
+                .ok_or_else(|| String::from("No previous selection evaluated to a number")),
+            _ => self
+                .variables
+                .get(name)
+                .cloned()
+                .ok_or_else(|| format!("Variable '{name}' is not defined")),
+        }
+    }
+
+    /// Gets the result of selection `n` (starting at 1)
+    fn selection(&self, n: &BigRational) -> Result<BigRational, String> {
+        n.to_integer()
+            .to_usize()
+            .filter(|_| n.is_integer())
+            .and_then(|n| n.checked_sub(1))
+            .and_then(|n| self.results.get(n))
+            .cloned()
+            .flatten()
+            .ok_or_else(|| format!("s[{n}] has not been evaluated yet"))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Number(BigRational),
+    Ident(String),
+    Op(char),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Number(n) => write!(f, "{n}"),
+            Self::Ident(s) => write!(f, "{s}"),
+            Self::Op(c) => write!(f, "{c}"),
+        }
+    }
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = s.char_indices().peekable();
+
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() || c == '.' {
+            let mut end = start;
+            let mut prev = c;
+            while let Some(&(i, c)) = chars.peek() {
+                // An exponent can have a sign, like `1e-3`
+                let is_exponent_sign = (c == '-' || c == '+') && matches!(prev, 'e' | 'E');
+                let is_hex = s[start..].starts_with("0x") || s[start..].starts_with("0X");
+                if !(c.is_ascii_alphanumeric() || c == '.' || c == '_' || is_exponent_sign)
+                    || (is_exponent_sign && is_hex)
+                {
+                    break;
+                }
+                end = i + c.len_utf8();
+                prev = c;
+                chars.next();
+            }
+            tokens.push(Token::Number(parse_number(&s[start..end])?));
+        } else if c.is_alphabetic() || c == '_' {
+            let mut end = start;
+            while let Some(&(i, c)) = chars.peek() {
+                if !(c.is_alphanumeric() || c == '_') {
+                    break;
+                }
+                end = i + c.len_utf8();
+                chars.next();
+            }
+            tokens.push(Token::Ident(s[start..end].to_string()));
+        } else if "+-*/%^(),;=[]".contains(c) {
+            tokens.push(Token::Op(c));
+            chars.next();
+        } else {
+            return Err(format!("Unexpected character '{c}'"));
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Parses an integer (decimal, `0x`, `0o`, or `0b`) or a decimal like `1_000.25e-3` exactly
+fn parse_number(s: &str) -> Result<BigRational, String> {
+    let invalid = || format!("Invalid number '{s}'");
+    let digits = s.replace('_', "");
+
+    let radix = match digits.get(..2) {
+        Some("0x" | "0X") => Some(16),
+        Some("0o" | "0O") => Some(8),
+        Some("0b" | "0B") => Some(2),
+        _ => None,
+    };
+    if let Some(radix) = radix {
+        return BigInt::parse_bytes(&digits.as_bytes()[2..], radix)
+            .map(BigRational::from_integer)
+            .ok_or_else(invalid);
+    }
+
+    let (mantissa, exponent) = match digits.split_once(['e', 'E']) {
+        Some((m, e)) => (m, e.parse::<i32>().map_err(|_| invalid())?),
+        None => (digits.as_str(), 0),
+    };
+    let (int, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    if int.is_empty() && fraction.is_empty() {
+        return Err(invalid());
+    }
+
+    let n = format!("{int}{fraction}")
+        .parse::<BigInt>()
+        .map_err(|_| invalid())?;
+    let exponent = exponent
+        .checked_sub(i32::try_from(fraction.len()).map_err(|_| invalid())?)
+        .filter(|e| e.abs() <= MAX_EXPONENT)
+        .ok_or_else(invalid)?;
+
+    Ok(BigRational::from_integer(n) * pow10(exponent))
+}
+
+/// `10^exponent` as a rational
+fn pow10(exponent: i32) -> BigRational {
+    BigRational::from_integer(BigInt::from(10)).pow(exponent)
+}
+
+/// Recursive descent parser, from lowest to highest precedence:
+///
+/// * `expr` - `a + b`, `a - b`
+/// * `term` - `a * b`, `a / b`, `a % b`
+/// * `unary` - `-a`, `+a`
+/// * `power` - `a ^ b` (right associative)
+/// * `atom` - numbers, variables, `s[N]`, function calls, and parentheses
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+    context: &'a ExactContext,
+}
+
+impl<'a> Parser<'a> {
+    const fn new(tokens: &'a [Token], context: &'a ExactContext) -> Self {
+        Self {
+            tokens,
+            pos: 0,
+            context,
+        }
+    }
+
+    fn parse(mut self) -> Result<BigRational, String> {
+        let value = self.expr()?;
+        self.tokens
+            .get(self.pos)
+            .map_or(Ok(value), |t| Err(format!("Unexpected '{t}'")))
+    }
+
+    fn peek_op(&self) -> Option<char> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Op(c)) => Some(*c),
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, op: char) -> Result<(), String> {
+        if self.peek_op() == Some(op) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.tokens.get(self.pos).map_or_else(
+                || format!("Expected '{op}' at end of expression"),
+                |t| format!("Expected '{op}', found '{t}'"),
+            ))
+        }
+    }
+
+    fn expr(&mut self) -> Result<BigRational, String> {
+        let mut value = self.term()?;
+        while let Some(op @ ('+' | '-')) = self.peek_op() {
+            self.pos += 1;
+            let rhs = self.term()?;
+            value = if op == '+' { value + rhs } else { value - rhs };
+        }
+        Ok(value)
+    }
+
+    fn term(&mut self) -> Result<BigRational, String> {
+        let mut value = self.unary()?;
+        while let Some(op @ ('*' | '/' | '%')) = self.peek_op() {
+            self.pos += 1;
+            let rhs = self.unary()?;
+            value = match op {
+                '*' => value * rhs,
+                _ if rhs.is_zero() => return Err(String::from("Division by zero")),
+                '/' => value / rhs,
+                _ => value % rhs,
+            };
+        }
+        Ok(value)
+    }
+
+    fn unary(&mut self) -> Result<BigRational, String> {
+        match self.peek_op() {
+            Some('-') => {
+                self.pos += 1;
+                Ok(-self.unary()?)
+            }
+            Some('+') => {
+                self.pos += 1;
+                self.unary()
+            }
+            _ => self.power(),
+        }
+    }
+
+    fn power(&mut self) -> Result<BigRational, String> {
+        let base = self.atom()?;
+        if self.peek_op() != Some('^') {
+            return Ok(base);
+        }
+        self.pos += 1;
+
+        let exponent = self.unary()?;
+        let exponent = exponent
+            .to_integer()
+            .to_i32()
+            .filter(|_| exponent.is_integer())
+            .ok_or_else(|| String::from("Exponent must be a whole number"))?;
+        if base.is_zero() && exponent < 0 {
+            return Err(String::from("Division by zero"));
+        }
+
+        // The result has at most this many bits, and 0, 1, and -1 stay the same size
+        let base_bits = base.numer().bits().max(base.denom().bits());
+        if base_bits > 1
+            && base_bits.saturating_mul(u64::from(exponent.unsigned_abs())) > MAX_POWER_BITS
+        {
+            return Err(format!(
+                "Result of ^ would be too large (over {MAX_POWER_BITS} bits)"
+            ));
+        }
+
+        Ok(base.pow(exponent))
+    }
+
+    fn atom(&mut self) -> Result<BigRational, String> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .ok_or_else(|| String::from("Unexpected end of expression"))?;
+        self.pos += 1;
+
+        match token {
+            Token::Number(n) => Ok(n.clone()),
+            Token::Op('(') => {
+                let value = self.expr()?;
+                self.expect(')')?;
+                Ok(value)
+            }
+            Token::Ident(name) if name == "s" && self.peek_op() == Some('[') => {
+                self.pos += 1;
+                let n = self.expr()?;
+                self.expect(']')?;
+                self.context.selection(&n)
+            }
+            Token::Ident(name) if self.peek_op() == Some('(') => {
+                self.pos += 1;
+                let mut args = Vec::new();
+                if self.peek_op() != Some(')') {
+                    args.push(self.expr()?);
+                    while self.peek_op() == Some(',') {
+                        self.pos += 1;
+                        args.push(self.expr()?);
+                    }
+                }
+                self.expect(')')?;
+                call_function(name, &args)
+            }
+            Token::Ident(name) => self.context.variable(name),
+            Token::Op(c) => Err(format!("Unexpected '{c}'")),
+        }
+    }
+}
+
+fn call_function(name: &str, args: &[BigRational]) -> Result<BigRational, String> {
+    match (name, args) {
+        ("abs", [x]) => Ok(x.abs()),
+        ("floor", [x]) => Ok(x.floor()),
+        ("ceil", [x]) => Ok(x.ceil()),
+        ("round", [x]) => Ok(x.round()),
+        ("trunc", [x]) => Ok(x.trunc()),
+        ("min", [first, rest @ ..]) => Ok(rest.iter().fold(first, |a, b| a.min(b)).clone()),
+        ("max", [first, rest @ ..]) => Ok(rest.iter().fold(first, |a, b| a.max(b)).clone()),
+        ("abs" | "floor" | "ceil" | "round" | "trunc" | "min" | "max", _) => Err(format!(
+            "Wrong number of arguments to '{name}': {}",
+            args.len()
+        )),
+        _ => Err(format!("Function '{name}' is not available with --exact")),
+    }
+}
+
+/// Number of decimal places needed to write `r` exactly, or `None` if it repeats forever (like `1/3`)
+pub fn exact_decimals(r: &BigRational) -> Option<u32> {
+    let mut denom = r.denom().clone();
+    let (two, five) = (BigInt::from(2), BigInt::from(5));
+    let (mut twos, mut fives) = (0, 0);
+
+    while denom.is_multiple_of(&two) {
+        denom /= &two;
+        twos += 1;
+    }
+    while denom.is_multiple_of(&five) {
+        denom /= &five;
+        fives += 1;
+    }
+
+    denom.is_one().then_some(twos.max(fives))
+}
+
+/// Position of the first significant digit of `r`, like `log10(abs(r))` rounded down
+///
+/// `r` must not be zero
+pub fn magnitude(r: &BigRational) -> i32 {
+    let r = r.abs();
+    let int_digits = r.to_integer().to_string().len();
+
+    if r >= BigRational::one() {
+        i32::try_from(int_digits).unwrap_or(i32::MAX) - 1
+    } else {
+        // Count how many times `r` must be multiplied by 10 to reach 1
+        let ten = BigRational::from_integer(BigInt::from(10));
+        let mut scaled = r;
+        let mut magnitude = 0;
+        while scaled < BigRational::one() && magnitude > -MAX_EXPONENT {
+            scaled *= &ten;
+            magnitude -= 1;
+        }
+        magnitude
+    }
+}
+
+/// Writes `r` with `decimals` decimal places, truncating any digits after that
+pub fn to_decimal(r: &BigRational, decimals: u32) -> String {
+    let scaled = (r * pow10(i32::try_from(decimals).unwrap_or(MAX_EXPONENT)))
+        .to_integer()
+        .abs();
+    let sign = if r.is_negative() && !scaled.is_zero() {
+        "-"
+    } else {
+        ""
+    };
+
+    if decimals == 0 {
+        return format!("{sign}{scaled}");
+    }
+
+    let digits = format!("{scaled:0>width$}", width = decimals as usize + 1);
+    let (int, fraction) = digits.split_at(digits.len() - decimals as usize);
+    format!("{sign}{int}.{fraction}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(exprs: &[&str]) -> Vec<Result<Option<String>, String>> {
+        let mut context = ExactContext::default();
+        exprs
+            .iter()
+            .map(|s| {
+                context.evaluate_next(s).map(|r| {
+                    r.map(|r| {
+                        exact_decimals(&r).map_or_else(|| r.to_string(), |d| to_decimal(&r, d))
+                    })
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let ok = |s: &str| Ok(Some(String::from(s)));
+
+        assert_eq!(
+            eval(&[
+                "0.1 + 0.2",
+                "19.99 * 3",
+                "2^64 * 2^64",
+                "340282366920938463463374607431768211455 + 1",
+                "1 / 3",
+                "-2^2 + 2^-1",
+                "7 % 3",
+                "1_000.5e-2",
+                "0xff + 0b1",
+                "max(1, 2.5, 2)",
+                "(1 + 2) * (3 - 4)",
+            ]),
+            vec![
+                ok("0.3"),
+                ok("59.97"),
+                ok("340282366920938463463374607431768211456"),
+                ok("340282366920938463463374607431768211456"),
+                ok("1/3"),
+                ok("-3.5"),
+                ok("1"),
+                ok("10.005"),
+                ok("256"),
+                ok("2.5"),
+                ok("-3"),
+            ]
+        );
+
+        assert_eq!(
+            eval(&["x = 1.10; y = 2", "x + y", "prev * 2", "s[2] + i"]),
+            vec![Ok(None), ok("3.1"), ok("6.2"), ok("7.1")]
+        );
+
+        assert!(eval(&["1 / 0"])[0].is_err());
+        assert!(eval(&["1 +"])[0].is_err());
+        assert!(eval(&["(1"])[0].is_err());
+        assert!(eval(&["y"])[0].is_err());
+        assert!(eval(&["i = 2"])[0].is_err());
+        assert!(eval(&["s[1]"])[0].is_err());
+        assert!(eval(&["2 ^ 0.5"])[0].is_err());
+        // The limit is on the result, not on each exponent
+        assert!(eval(&["(2^10000)^10000"])[0].is_err());
+        assert!(eval(&["2^2000000"])[0].is_err());
+        assert_eq!(
+            eval(&["1^2000000", "2^100000 / 2^99999"]),
+            vec![ok("1"), ok("2")]
+        );
+    }
+
+    #[test]
+    fn test_format() {
+        let r = |n: i64, d: i64| BigRational::new(BigInt::from(n), BigInt::from(d));
+
+        assert_eq!(exact_decimals(&r(1, 8)), Some(3));
+        assert_eq!(exact_decimals(&r(3, 1)), Some(0));
+        assert_eq!(exact_decimals(&r(1, 3)), None);
+        assert_eq!(magnitude(&r(12345, 1)), 4);
+        assert_eq!(magnitude(&r(-1, 1)), 0);
+        assert_eq!(magnitude(&r(12, 1000)), -2);
+        assert_eq!(to_decimal(&r(-1, 8), 3), "-0.125");
+        assert_eq!(to_decimal(&r(5, 1), 2), "5.00");
+        assert_eq!(to_decimal(&r(1, 3), 4), "0.3333");
+    }
+}
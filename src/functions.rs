@@ -0,0 +1,378 @@
+//! Functions for `math-eval` and `incr --eval`, on top of evalexpr's built-in functions
+//!
+//! Integers can be written as `0xff`, `0o17`, or `0b1010` in expressions, and functions that take integers also accept them as strings (`bitand("0b1010", 6)`)
+use evalexpr::{
+    ContextWithMutableFunctions, EvalexprError, EvalexprResult, Function, HashMapContext, Value,
+};
+use std::{borrow::Cow, ops::RangeInclusive};
+
+type FunctionPointer = fn(&Value) -> EvalexprResult<Value>;
+
+/// Creates a context with every function in this module
+pub fn context() -> HashMapContext {
+    let functions: [(&str, FunctionPointer); 21] = [
+        ("int", int),
+        ("hex", |a| format_radix(a, 16)),
+        ("oct", |a| format_radix(a, 8)),
+        ("bin", |a| format_radix(a, 2)),
+        ("bitand", |a| int_op(a, |x, y| Some(x & y))),
+        ("bitor", |a| int_op(a, |x, y| Some(x | y))),
+        ("bitxor", |a| int_op(a, |x, y| Some(x ^ y))),
+        ("bitnot", |a| Ok(Value::Int(!to_int(a)?))),
+        ("shl", |a| {
+            int_op(a, |x, y| x.checked_shl(u32::try_from(y).ok()?))
+        }),
+        ("shr", |a| {
+            int_op(a, |x, y| x.checked_shr(u32::try_from(y).ok()?))
+        }),
+        ("gcd", |a| int_fold(a, gcd)),
+        ("lcm", |a| {
+            int_fold(a, |x, y| {
+                if x == 0 || y == 0 {
+                    Some(0)
+                } else {
+                    (x / gcd(x, y)?).checked_mul(y).map(i64::abs)
+                }
+            })
+        }),
+        ("clamp", clamp),
+        ("round_to", round_to),
+        ("hypot", |a| {
+            let args = arguments(a, 2..=2)?;
+            Ok(Value::Float(
+                args[0].as_number()?.hypot(args[1].as_number()?),
+            ))
+        }),
+        ("pct_change", pct_change),
+        ("len", |a| {
+            let len = match a {
+                Value::Tuple(t) => t.len(),
+                v => to_text(v).chars().count(),
+            };
+            Ok(Value::Int(i64::try_from(len).unwrap_or(i64::MAX)))
+        }),
+        ("upper", |a| Ok(Value::String(to_text(a).to_uppercase()))),
+        ("lower", |a| Ok(Value::String(to_text(a).to_lowercase()))),
+        ("pad", |a| pad(a, true)),
+        ("rpad", |a| pad(a, false)),
+    ];
+
+    let mut context = HashMapContext::new();
+    for (name, function) in functions {
+        // Setting a function on a `HashMapContext` cannot fail
+        let _ = context.set_function(name.to_string(), Function::new(function));
+    }
+    context
+}
+
+/// Replaces integers like `0xff`, `0o17`, and `0b1010` in `expr` with their decimal value, since evalexpr cannot parse them
+///
+/// Strings in `expr` are left alone
+///
+/// # Examples
+///
+/// ```
+/// assert_eq!(expand_radix_literals("0xff + 1"), "255 + 1");
+/// assert_eq!(expand_radix_literals(r#"len("0xff")"#), r#"len("0xff")"#);
+/// ```
+pub fn expand_radix_literals(expr: &str) -> Cow<'_, str> {
+    if !expr.contains(['x', 'X', 'o', 'O', 'b', 'B']) {
+        return Cow::Borrowed(expr);
+    }
+
+    let mut ret = String::with_capacity(expr.len());
+    let mut in_string = false;
+    let mut prev = None;
+    let mut chars = expr.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        let at_word_start = !matches!(prev, Some(p) if p == '_' || char::is_alphanumeric(p));
+        if c == '"' && prev != Some('\\') {
+            in_string = !in_string;
+        }
+        prev = Some(c);
+
+        if in_string || c != '0' || !at_word_start {
+            ret.push(c);
+            continue;
+        }
+
+        // Take the whole word, like `0x1f`
+        let mut end = start + c.len_utf8();
+        while let Some(&(i, c)) = chars.peek() {
+            if !(c.is_alphanumeric() || c == '_') {
+                break;
+            }
+            end = i + c.len_utf8();
+            prev = Some(c);
+            chars.next();
+        }
+        let word = &expr[start..end];
+
+        match parse_int(word) {
+            Some(i) if matches!(word.get(1..2), Some("x" | "X" | "o" | "O" | "b" | "B")) => {
+                ret.push_str(&i.to_string());
+            }
+            _ => ret.push_str(word),
+        }
+    }
+
+    Cow::Owned(ret)
+}
+
+/// Parses an integer like `-42`, `0xff`, `0o17`, or `0b1_010`
+fn parse_int(s: &str) -> Option<i64> {
+    let s = s.trim().replace('_', "");
+    let (negative, unsigned) = s
+        .strip_prefix('-')
+        .map_or_else(|| (false, s.strip_prefix('+').unwrap_or(&s)), |u| (true, u));
+
+    let (digits, radix) = match unsigned.get(..2) {
+        Some("0x" | "0X") => (&unsigned[2..], 16),
+        Some("0o" | "0O") => (&unsigned[2..], 8),
+        Some("0b" | "0B") => (&unsigned[2..], 2),
+        _ => (unsigned, 10),
+    };
+    // `from_str_radix` allows another sign after the prefix
+    if digits.starts_with(['-', '+']) {
+        return None;
+    }
+
+    let i = i64::from_str_radix(digits, radix).ok()?;
+    if negative {
+        i.checked_neg()
+    } else {
+        Some(i)
+    }
+}
+
+/// Gets the arguments of a function, which evalexpr passes as a tuple if there is more than one
+fn arguments(argument: &Value, count: RangeInclusive<usize>) -> EvalexprResult<Vec<Value>> {
+    let args = match argument {
+        Value::Tuple(t) => t.clone(),
+        Value::Empty => Vec::new(),
+        v => vec![v.clone()],
+    };
+
+    if count.contains(&args.len()) {
+        Ok(args)
+    } else {
+        Err(EvalexprError::WrongFunctionArgumentAmount {
+            expected: if args.len() < *count.start() {
+                *count.start()
+            } else {
+                *count.end()
+            },
+            actual: args.len(),
+        })
+    }
+}
+
+/// Gets an integer from an int, a whole float, or a string like `0xff`
+fn to_int(v: &Value) -> EvalexprResult<i64> {
+    match v {
+        Value::Int(i) => Ok(*i),
+        // Every float in this range is a whole number that fits in an i64
+        Value::Float(f) if f.fract() == 0.0 && f.abs() < 2_f64.powi(63) => Ok(*f as i64),
+        Value::String(s) => parse_int(s)
+            .ok_or_else(|| EvalexprError::CustomMessage(format!("Not an integer: '{s}'"))),
+        v => Err(EvalexprError::expected_int(v.clone())),
+    }
+}
+
+/// Gets the text of a value, without the quotes evalexpr puts around strings
+fn to_text(v: &Value) -> String {
+    match v {
+        Value::String(s) => s.clone(),
+        v => v.to_string(),
+    }
+}
+
+/// Parses a string like `0xff` (or a number) into an int
+fn int(argument: &Value) -> EvalexprResult<Value> {
+    to_int(argument).map(Value::Int)
+}
+
+/// Writes an integer in hex, octal, or binary with a prefix like `0x`, optionally zero padded to a number of digits
+fn format_radix(argument: &Value, radix: u32) -> EvalexprResult<Value> {
+    let args = arguments(argument, 1..=2)?;
+    let i = to_int(&args[0])?;
+    let width = args
+        .get(1)
+        .map(|w| to_int(w).map(|w| usize::try_from(w).unwrap_or(0)))
+        .transpose()?
+        .unwrap_or(0);
+
+    let sign = if i < 0 { "-" } else { "" };
+    let n = i.unsigned_abs();
+    Ok(Value::String(match radix {
+        16 => format!("{sign}0x{n:0width$x}"),
+        8 => format!("{sign}0o{n:0width$o}"),
+        _ => format!("{sign}0b{n:0width$b}"),
+    }))
+}
+
+/// Applies a binary operation to two integers, failing if it returns `None`
+fn int_op(argument: &Value, op: fn(i64, i64) -> Option<i64>) -> EvalexprResult<Value> {
+    let args = arguments(argument, 2..=2)?;
+    let (x, y) = (to_int(&args[0])?, to_int(&args[1])?);
+    op(x, y)
+        .map(Value::Int)
+        .ok_or_else(|| EvalexprError::CustomMessage(format!("Result overflows with {x} and {y}")))
+}
+
+/// Combines any number of integers with `op`, like `gcd(12, 18, 30)`
+fn int_fold(argument: &Value, op: fn(i64, i64) -> Option<i64>) -> EvalexprResult<Value> {
+    let args = arguments(argument, 1..=usize::MAX)?;
+    let mut ret = to_int(&args[0])?;
+    for arg in &args[1..] {
+        ret = op(ret, to_int(arg)?)
+            .ok_or_else(|| EvalexprError::CustomMessage(String::from("Result overflows")))?;
+    }
+    Ok(Value::Int(ret))
+}
+
+/// Greatest common divisor, or `None` if it does not fit in an i64 (`gcd(i64::MIN, 0)`)
+fn gcd(x: i64, y: i64) -> Option<i64> {
+    let (mut a, mut b) = (x.unsigned_abs(), y.unsigned_abs());
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    i64::try_from(a).ok()
+}
+
+/// `clamp(x, min, max)`, keeping ints as ints
+fn clamp(argument: &Value) -> EvalexprResult<Value> {
+    let args = arguments(argument, 3..=3)?;
+
+    if let [Value::Int(x), Value::Int(min), Value::Int(max)] = args.as_slice() {
+        if min > max {
+            return Err(EvalexprError::CustomMessage(format!(
+                "clamp: {min} is greater than {max}"
+            )));
+        }
+        return Ok(Value::Int(*x.max(min).min(max)));
+    }
+
+    let (x, min, max) = (
+        args[0].as_number()?,
+        args[1].as_number()?,
+        args[2].as_number()?,
+    );
+    if min > max || min.is_nan() || max.is_nan() {
+        return Err(EvalexprError::CustomMessage(format!(
+            "clamp: {min} is greater than {max}"
+        )));
+    }
+    Ok(Value::Float(x.clamp(min, max)))
+}
+
+/// `round_to(x, places)` rounds to a number of decimal places (or tens, hundreds, etc if `places` is negative), away from zero if it is halfway
+fn round_to(argument: &Value) -> EvalexprResult<Value> {
+    let args = arguments(argument, 2..=2)?;
+    let places = i32::try_from(to_int(&args[1])?)
+        .map_err(|_| EvalexprError::CustomMessage(String::from("round_to: too many places")))?;
+
+    if let Value::Int(i) = args[0] {
+        if places >= 0 {
+            return Ok(Value::Int(i));
+        }
+    }
+
+    let x = args[0].as_number()?;
+    let scale = 10_f64.powi(places);
+    Ok(Value::Float(if (x * scale).is_finite() && scale > 0.0 {
+        (x * scale).round() / scale
+    } else {
+        x
+    }))
+}
+
+/// `pct_change(old, new)` is the change from `old` to `new` in percent
+fn pct_change(argument: &Value) -> EvalexprResult<Value> {
+    let args = arguments(argument, 2..=2)?;
+    let (old, new) = (args[0].as_number()?, args[1].as_number()?);
+
+    if old == 0.0 {
+        return Err(EvalexprError::CustomMessage(String::from(
+            "pct_change: the old value is zero",
+        )));
+    }
+    Ok(Value::Float((new - old) / old.abs() * 100.0))
+}
+
+/// `pad(value, width, fill)` pads `value` to `width` characters with `fill` (a space by default)
+fn pad(argument: &Value, left: bool) -> EvalexprResult<Value> {
+    let args = arguments(argument, 2..=3)?;
+    let text = to_text(&args[0]);
+    let width = usize::try_from(to_int(&args[1])?).unwrap_or(0);
+    let fill = match args.get(2).map(to_text) {
+        None => ' ',
+        Some(f) if f.chars().count() == 1 => f.chars().next().unwrap_or(' '),
+        Some(f) => {
+            return Err(EvalexprError::CustomMessage(format!(
+                "pad: fill must be one character, not '{f}'"
+            )))
+        }
+    };
+
+    let padding = fill
+        .to_string()
+        .repeat(width.saturating_sub(text.chars().count()));
+    Ok(Value::String(if left {
+        padding + &text
+    } else {
+        text + &padding
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evalexpr::eval_with_context;
+
+    fn eval(expr: &str) -> EvalexprResult<Value> {
+        eval_with_context(&expand_radix_literals(expr), &context())
+    }
+
+    #[test]
+    fn test_functions() {
+        let s = |s: &str| Ok(Value::String(String::from(s)));
+
+        assert_eq!(eval("0xff + 0b1 + 0o10"), Ok(Value::Int(264)));
+        assert_eq!(eval(r#"int("0x1F")"#), Ok(Value::Int(31)));
+        assert_eq!(eval("hex(255)"), s("0xff"));
+        assert_eq!(eval("bin(5, 8)"), s("0b00000101"));
+        assert_eq!(eval("oct(-8)"), s("-0o10"));
+        assert_eq!(eval(r#"bin(bitand("0b1010", 0b0110))"#), s("0b10"));
+        assert_eq!(eval("bitxor(0b1010, 0b0110)"), Ok(Value::Int(12)));
+        assert_eq!(eval("shl(1, 4)"), Ok(Value::Int(16)));
+        assert!(eval("shl(1, 64)").is_err());
+        assert_eq!(eval("gcd(12, 18, 30)"), Ok(Value::Int(6)));
+        assert_eq!(eval("lcm(4, 6)"), Ok(Value::Int(12)));
+        assert_eq!(eval("clamp(15, 0, 10)"), Ok(Value::Int(10)));
+        assert_eq!(eval("clamp(-0.5, 0, 1)"), Ok(Value::Float(0.0)));
+        assert!(eval("clamp(1, 2, 0)").is_err());
+        assert_eq!(eval("round_to(2.345, 2)"), Ok(Value::Float(2.35)));
+        assert_eq!(eval("round_to(1234, -2)"), Ok(Value::Float(1200.0)));
+        assert_eq!(eval("hypot(3, 4)"), Ok(Value::Float(5.0)));
+        assert_eq!(eval("pct_change(50, 75)"), Ok(Value::Float(50.0)));
+        assert!(eval("pct_change(0, 1)").is_err());
+        assert_eq!(eval(r#"len("héllo")"#), Ok(Value::Int(5)));
+        assert_eq!(eval(r#"upper("abc")"#), s("ABC"));
+        assert_eq!(eval(r#"pad(7, 3, "0")"#), s("007"));
+        assert_eq!(eval(r#"rpad("ab", 4, ".")"#), s("ab.."));
+        assert!(eval(r#"pad(7, 3, "00")"#).is_err());
+        assert!(eval("gcd()").is_err());
+    }
+
+    #[test]
+    fn test_expand_radix_literals() {
+        assert_eq!(expand_radix_literals("0xff + 1"), "255 + 1");
+        assert_eq!(expand_radix_literals("0b1010*0o7"), "10*7");
+        assert_eq!(expand_radix_literals("-0x10"), "-16");
+        assert_eq!(expand_radix_literals(r#"len("0xff")"#), r#"len("0xff")"#);
+        assert_eq!(expand_radix_literals("a0x1 + x0b1"), "a0x1 + x0b1");
+        assert_eq!(expand_radix_literals("0xzz + 0 + 01"), "0xzz + 0 + 01");
+    }
+}
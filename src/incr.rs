@@ -1,37 +1,104 @@
-use evalexpr::{eval, Value};
-use kakplugin::{get_selections, set_selections, KakError};
-use std::borrow::Cow;
+use crate::{
+    date::{self, Span},
+    functions,
+    utils::{group_thousands, ErrorOptions, EvalError},
+};
+use evalexpr::{eval_with_context, HashMapContext, Value};
+use kakplugin::{get_selections_with_desc, set_selections, KakError};
+use regex::Regex;
+use std::{borrow::Cow, fmt, str::FromStr};
+
+/// Matches hex, octal, and binary integers, decimals with thousands separators, and plain decimals (in that order)
+const NUMBER_REGEX: &str = r"0[xX][0-9a-fA-F]+|0[oO][0-7]+|0[bB][01]+|[0-9]{1,3}(?:[,_][0-9]{3})+(?:\.[0-9]+)?|[0-9]+(?:\.[0-9]+)?";
 
 #[derive(clap::Args, Debug)]
 pub struct Options {
-    #[clap(index = 1, help = "Amount to increment/decrement", default_value = "1")]
-    amount: isize,
+    #[clap(
+        index = 1,
+        allow_hyphen_values = true,
+        help = "Amount to increment/decrement, or a time span like '3d' or '2h' to change dates",
+        default_value = "1"
+    )]
+    amount: Amount,
+    #[clap(
+        short,
+        long,
+        help = "Evaluate each selection as a math expression instead of changing the number inside it"
+    )]
+    eval: bool,
+    #[clap(flatten)]
+    errors: ErrorOptions,
 }
 
-pub fn incr(options: &Options, should_increment: bool) -> Result<String, KakError> {
-    let mut err_count: usize = 0;
+#[derive(Clone, Copy, Debug)]
+enum Amount {
+    Number(isize),
+    Span(Span),
+}
 
-    let selections = get_selections(Some("_"))?;
+impl FromStr for Amount {
+    type Err = KakError;
 
-    set_selections(selections.iter().map(|s| {
-        match eval(&format!(
-            "{s}{}{}",
-            if should_increment { "+" } else { "-" },
-            options.amount
-        )) {
-            Ok(Value::Float(f)) => Cow::Owned(f.to_string()),
-            Ok(Value::Int(f)) => Cow::Owned(f.to_string()),
-            Ok(_) => Cow::Borrowed(""),
-            Err(e) => {
-                eprintln!("Error: {:?}", e);
-                err_count = err_count.saturating_add(1);
-                // Set the selection to empty
-                Cow::Borrowed("")
-            }
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse()
+            .map_or_else(|_| s.parse().map(Self::Span), |n| Ok(Self::Number(n)))
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Number(n) => write!(f, "{n}"),
+            Self::Span(s) => write!(f, "{s}"),
         }
-    }))?;
+    }
+}
+
+pub fn incr(options: &Options, should_increment: bool) -> Result<String, KakError> {
+    if options.eval && matches!(options.amount, Amount::Span(_)) {
+        return Err(KakError::CustomStatic(
+            "Time spans cannot be used with --eval. Use math-eval instead",
+        ));
+    }
+
+    let number_regex = Regex::new(NUMBER_REGEX)
+        .map_err(|e| KakError::Custom(format!("Could not compile number regex: {e}")))?;
+    let context = functions::context();
+    let mut errors = Vec::new();
+
+    // Not trimmed, so a failed selection is kept exactly and line selections keep their newline
+    let selections = get_selections_with_desc(None)?;
 
-    Ok(if err_count == 0 {
+    let new_selections: Vec<Cow<'_, str>> = selections
+        .iter()
+        .map(|s| {
+            incr_selection(
+                &s.content,
+                options,
+                should_increment,
+                &number_regex,
+                &context,
+            )
+            .map_or_else(
+                |error| {
+                    errors.push(EvalError {
+                        desc: s.desc,
+                        content: &s.content,
+                        error,
+                    });
+                    Cow::Borrowed(options.errors.replacement(&s.content))
+                },
+                Cow::Owned,
+            )
+        })
+        .collect();
+
+    // Abort before anything is written if the user asked for it
+    let error_details = options.errors.report(&errors)?;
+
+    set_selections(new_selections.iter())?;
+
+    let msg = if errors.is_empty() {
         format!(
             "{} {} selections by {}",
             if should_increment { "Incr" } else { "Decr" },
@@ -42,10 +109,199 @@ pub fn incr(options: &Options, should_increment: bool) -> Result<String, KakErro
         format!(
             "{} {} selections by {} ({} error{})",
             if should_increment { "Incr" } else { "Decr" },
-            selections.len().saturating_sub(err_count),
+            selections.len().saturating_sub(errors.len()),
             options.amount,
-            err_count,
-            if err_count == 1 { "" } else { "s" }
+            errors.len(),
+            if errors.len() == 1 { "" } else { "s" }
+        )
+    };
+
+    // The failed selections are shown as the message's details
+    match error_details {
+        Some(details) => Err(KakError::CustomDetails(msg, details)),
+        None => Ok(msg),
+    }
+}
+
+/// Increments or decrements a single selection, returning a description of the error if it fails
+fn incr_selection(
+    s: &str,
+    options: &Options,
+    should_increment: bool,
+    number_regex: &Regex,
+    context: &HashMapContext,
+) -> Result<String, String> {
+    match options.amount {
+        Amount::Number(amount) if options.eval => {
+            // Evaluate `"{s}+{amount}"` without the surrounding whitespace, which is kept around the result
+            let trimmed = s.trim();
+            let (leading, rest) = s.split_at(s.len() - s.trim_start().len());
+            let trailing = &rest[trimmed.len()..];
+
+            let expr = format!(
+                "{trimmed}{}{amount}",
+                if should_increment { "+" } else { "-" }
+            );
+            match eval_with_context(&functions::expand_radix_literals(&expr), context) {
+                Ok(Value::Float(f)) => Ok(format!("{leading}{f}{trailing}")),
+                Ok(Value::Int(f)) => Ok(format!("{leading}{f}{trailing}")),
+                Ok(v) => Err(format!("Result is not a number: {v}")),
+                Err(e) => Err(e.to_string()),
+            }
+        }
+        Amount::Number(amount) => add_to_number(
+            s,
+            if should_increment {
+                amount as i128
+            } else {
+                -(amount as i128)
+            },
+            number_regex,
+        )
+        .ok_or_else(|| String::from("No number found, or the result is too large")),
+        Amount::Span(span) => date::add_to_date(
+            s,
+            if should_increment {
+                span
+            } else {
+                span.negate()
+            },
+        )
+        .ok_or_else(|| String::from("No date found, or the time span cannot be added to it")),
+    }
+}
+
+/// Adds `amount` to the first number in `s`, keeping the rest of `s` and the number's formatting
+///
+/// Returns `None` if there is no number in `s`, or if the result overflows
+///
+/// # Examples
+///
+/// ```
+/// assert_eq!(add_to_number("v007", 1, &re).as_deref(), Some("v008"));
+/// assert_eq!(add_to_number("0x0f", 1, &re).as_deref(), Some("0x10"));
+/// assert_eq!(add_to_number("$1,999.50", 1, &re).as_deref(), Some("$2,000.50"));
+/// ```
+fn add_to_number(s: &str, amount: i128, number_regex: &Regex) -> Option<String> {
+    let m = number_regex.find(s)?;
+    let (before, number, after) = (&s[..m.start()], m.as_str(), &s[m.end()..]);
+
+    // A sign only belongs to the number if it is not joining two words, like in `foo-1`
+    let mut before_chars = before.chars();
+    let sign = match (before_chars.next_back(), before_chars.next_back()) {
+        (Some(c @ ('-' | '+')), None) => Some(c),
+        (Some(c @ ('-' | '+')), Some(p)) if !p.is_alphanumeric() && p != '_' => Some(c),
+        _ => None,
+    };
+    let before = &before[..before.len() - sign.map_or(0, char::len_utf8)];
+
+    let new_number = add_to_formatted(sign, number, amount)?;
+
+    Some(format!("{before}{new_number}{after}"))
+}
+
+/// Adds `amount` to `number` (without its sign), formatting the result like `number`
+fn add_to_formatted(sign: Option<char>, number: &str, amount: i128) -> Option<String> {
+    let (prefix, digits, radix) = match number.get(..2) {
+        Some(p @ ("0x" | "0X")) => (p, &number[2..], 16),
+        Some(p @ ("0o" | "0O")) => (p, &number[2..], 8),
+        Some(p @ ("0b" | "0B")) => (p, &number[2..], 2),
+        _ => ("", number, 10),
+    };
+
+    // The thousands separator is the first character that is not a digit or a decimal point
+    let separator = digits.chars().find(|c| !c.is_ascii_hexdigit() && *c != '.');
+    let digits = separator.map_or(Cow::Borrowed(digits), |sep| {
+        Cow::Owned(digits.replace(sep, ""))
+    });
+    let (int_digits, fraction) = digits
+        .split_once('.')
+        .map_or_else(|| (digits.as_ref(), ""), |(i, f)| (i, f));
+
+    // Work in units of the last decimal place so precision is kept
+    let scale = 10_i128.checked_pow(u32::try_from(fraction.len()).ok()?)?;
+    let magnitude = i128::from_str_radix(int_digits, radix)
+        .ok()?
+        .checked_mul(scale)?
+        .checked_add(if fraction.is_empty() {
+            0
+        } else {
+            fraction.parse::<i128>().ok()?
+        })?;
+    let value = if sign == Some('-') {
+        -magnitude
+    } else {
+        magnitude
+    };
+    let new_value = value.checked_add(amount.checked_mul(scale)?)?;
+    let new_magnitude = new_value.unsigned_abs();
+
+    let mut new_int = match radix {
+        16 if int_digits.chars().any(|c| c.is_ascii_uppercase()) => {
+            format!("{new_magnitude:X}")
+        }
+        16 => format!("{new_magnitude:x}"),
+        8 => format!("{new_magnitude:o}"),
+        2 => format!("{new_magnitude:b}"),
+        _ => (new_magnitude / scale.unsigned_abs()).to_string(),
+    };
+
+    // Keep the width of zero padded numbers, and always keep the width of hex, octal, and binary numbers
+    if radix != 10 || (int_digits.len() > 1 && int_digits.starts_with('0')) {
+        new_int = format!("{new_int:0>width$}", width = int_digits.len());
+    }
+
+    if let Some(sep) = separator {
+        new_int = group_thousands(&new_int, sep);
+    }
+
+    let new_sign = match (new_value < 0, sign) {
+        (true, _) => "-",
+        (false, Some('+')) => "+",
+        (false, _) => "",
+    };
+
+    Some(if fraction.is_empty() {
+        format!("{new_sign}{prefix}{new_int}")
+    } else {
+        format!(
+            "{new_sign}{prefix}{new_int}.{:0>width$}",
+            new_magnitude % scale.unsigned_abs(),
+            width = fraction.len()
         )
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_to_number() {
+        let re = Regex::new(NUMBER_REGEX).unwrap();
+        let add = |s, amount| add_to_number(s, amount, &re);
+
+        assert_eq!(add("7", 1).as_deref(), Some("8"));
+        assert_eq!(add("007", 1).as_deref(), Some("008"));
+        assert_eq!(add("099", 1).as_deref(), Some("100"));
+        assert_eq!(add("0x0f", 1).as_deref(), Some("0x10"));
+        assert_eq!(add("0xFF", 1).as_deref(), Some("0x100"));
+        assert_eq!(add("0b0111", 1).as_deref(), Some("0b1000"));
+        assert_eq!(add("1.50", 1).as_deref(), Some("2.50"));
+        assert_eq!(add("-0.25", 1).as_deref(), Some("0.75"));
+        assert_eq!(add("1", -3).as_deref(), Some("-2"));
+        assert_eq!(add("-1", 3).as_deref(), Some("2"));
+        assert_eq!(add("+1", 3).as_deref(), Some("+4"));
+        assert_eq!(add("1,999", 1).as_deref(), Some("2,000"));
+        assert_eq!(add("999_999", 1).as_deref(), Some("1_000_000"));
+        assert_eq!(add("x = -5;", 10).as_deref(), Some("x = 5;"));
+        assert_eq!(add("item-5", 1).as_deref(), Some("item-6"));
+        assert_eq!(add("width: 12px", -2).as_deref(), Some("width: 10px"));
+        // Whole line selections keep their indentation and newline
+        assert_eq!(
+            add("    width: 12px;\n", 1).as_deref(),
+            Some("    width: 13px;\n")
+        );
+        assert_eq!(add("abc", 1), None);
+    }
+}
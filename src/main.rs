@@ -10,7 +10,11 @@
 #![feature(array_chunks)]
 
 mod box_;
+mod date;
+mod enumerate;
 mod errors;
+mod exact;
+mod functions;
 mod incr;
 mod invert;
 mod join;
@@ -21,8 +25,10 @@ mod rev;
 mod set;
 mod shuf;
 mod sort;
+mod stats;
 mod trim;
 mod uniq;
+mod units;
 mod utils;
 mod xargs;
 mod xlookup;
@@ -56,6 +62,8 @@ enum Commands {
     Invert(invert::Options),
     #[clap(about = "Evaluate selections as a math expression", visible_aliases = &["bc", "eval"])]
     MathEval(math_eval::Options),
+    #[clap(about = "Show statistics about numeric selections", visible_aliases = &["sum"])]
+    Stats(stats::Options),
     #[clap(about = "Pad all selections by some specifier")]
     Pad(pad::Options),
     #[clap(about = "Trim every selection")]
@@ -72,6 +80,8 @@ enum Commands {
     Decr(incr::Options),
     #[clap(about = "Decrement selections")]
     Incr(incr::Options),
+    #[clap(about = "Replace selections with a sequence of numbers", visible_aliases = &["seq", "enum"])]
+    Enumerate(enumerate::Options),
     #[clap(about = "Reverse selections")]
     Rev(rev::Options),
     #[clap(about = "Join selections")]
@@ -120,6 +130,7 @@ fn run() -> Result<String, KakError> {
         Commands::Uniq(o) => uniq::uniq(o),
         Commands::Invert(o) => invert::invert(o),
         Commands::MathEval(o) => math_eval::math_eval(o),
+        Commands::Stats(o) => stats::stats(o),
         Commands::Pad(o) => pad::pad(o),
         Commands::Trim(o) => trim::trim(o),
         Commands::Set(o) => set::set(o),
@@ -128,6 +139,7 @@ fn run() -> Result<String, KakError> {
         Commands::Xlookup(o) => xlookup::xlookup(o),
         Commands::Incr(o) => incr::incr(o, true),
         Commands::Decr(o) => incr::incr(o, false),
+        Commands::Enumerate(o) => enumerate::enumerate(o),
         Commands::Rev(o) => rev::rev(o),
         Commands::Join(o) => join::join(o),
         Commands::KeepEvery(o) => keep_every::keep_every(o),
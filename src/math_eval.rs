@@ -1,34 +1,743 @@
-use evalexpr::{eval, Value};
-use kakplugin::{get_selections, set_selections, KakError};
+use crate::{
+    date,
+    exact::{self, ExactContext},
+    functions,
+    units::{self, Unit},
+    utils::{group_thousands, ErrorOptions, EvalError},
+};
+use evalexpr::{
+    eval_with_context_mut, Context, ContextWithMutableVariables, EvalexprError, EvalexprResult,
+    HashMapContext, Value,
+};
+use kakplugin::{get_selections_with_desc, set_selections, KakError};
+use num_bigint::BigInt;
+use num_integer::Integer;
+use num_rational::BigRational;
+use num_traits::{Signed, Zero};
+use regex::Regex;
 use std::borrow::Cow;
 
+/// Prefix of the variable that `s[N]` is rewritten to, since evalexpr identifiers cannot contain brackets
+const SELECTION_REF_PREFIX: &str = "__s";
+/// Matches `s[N]`
+const SELECTION_REF_REGEX: &str = r"\bs\[\s*(\d+)\s*\]";
+/// Decimal places to round exact results that repeat forever (like `1/3`) to, when there is no `--precision`
+const EXACT_MAX_DECIMALS: i32 = 20;
+
 #[derive(clap::Args, Debug)]
-pub struct Options;
-pub fn math_eval(_options: &Options) -> Result<String, KakError> {
-    let mut err_count: usize = 0;
+pub struct Options {
+    #[clap(
+        short = 'x',
+        long,
+        help = "Evaluate with exact decimals and integers of any size. Only arithmetic, variables, abs, floor, ceil, round, trunc, min, and max are supported"
+    )]
+    exact: bool,
+    #[clap(flatten)]
+    format: FormatOptions,
+    #[clap(flatten)]
+    errors: ErrorOptions,
+}
+
+/// How results are written back to the selections
+#[derive(clap::Args, Clone, Debug, Default)]
+struct FormatOptions {
+    #[clap(
+        short,
+        long,
+        value_name = "DIGITS",
+        value_parser = clap::value_parser!(u32).range(..=1000),
+        help = "Round results to this many decimal places"
+    )]
+    precision: Option<u32>,
+    #[clap(
+        short = 'g',
+        long,
+        value_name = "DIGITS",
+        conflicts_with = "precision",
+        value_parser = clap::value_parser!(u32).range(1..=1000),
+        help = "Round results to this many significant digits"
+    )]
+    significant: Option<u32>,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t,
+        help = "How to round results when using --precision or --significant"
+    )]
+    rounding: Rounding,
+    #[clap(
+        short,
+        long,
+        value_name = "SEPARATOR",
+        help = "Separate every three digits of results with this character"
+    )]
+    thousands: Option<char>,
+    #[clap(
+        short,
+        long,
+        value_enum,
+        default_value_t,
+        conflicts_with_all = ["precision", "significant"],
+        help = "Base to write results in. Results must be whole numbers unless this is decimal"
+    )]
+    radix: Radix,
+    #[clap(
+        short,
+        long,
+        help = "Write results that are whole numbers without decimal places, even with --precision or --significant"
+    )]
+    keep_integers: bool,
+    #[clap(
+        long,
+        value_name = "UNIT",
+        value_parser = units::find_unit,
+        help = "Convert results with units to this unit, like 'MiB' or 'h'"
+    )]
+    to: Option<&'static Unit>,
+}
+
+/// How to round results to a number of digits
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum Rounding {
+    /// Round to the nearest number, away from zero if it is halfway
+    #[default]
+    HalfUp,
+    /// Round to the nearest number, to the even one if it is halfway
+    HalfEven,
+    /// Round away from zero
+    Up,
+    /// Round towards zero
+    Down,
+    /// Round towards negative infinity
+    Floor,
+    /// Round towards positive infinity
+    Ceiling,
+}
+
+impl Rounding {
+    /// Rounds `f` to an integer
+    fn round(self, f: f64) -> f64 {
+        match self {
+            Self::HalfEven if (f.fract().abs() - 0.5).abs() < f64::EPSILON => {
+                2.0 * (f / 2.0).round()
+            }
+            Self::HalfUp | Self::HalfEven => f.round(),
+            Self::Up if f < 0.0 => f.floor(),
+            Self::Up | Self::Ceiling => f.ceil(),
+            Self::Down => f.trunc(),
+            Self::Floor => f.floor(),
+        }
+    }
+
+    /// Rounds `f` to `decimals` decimal places, or to tens, hundreds, etc if `decimals` is negative
+    fn round_to(self, f: f64, decimals: i32) -> f64 {
+        let scale = 10_f64.powi(decimals);
+        let scaled = f * scale;
+
+        if scaled.is_finite() && scale.is_finite() && scale > 0.0 {
+            self.round(scaled) / scale
+        } else {
+            // `f` cannot have any digits this small, so there is nothing to round
+            f
+        }
+    }
+
+    /// Rounds `r` to `decimals` decimal places exactly, or to tens, hundreds, etc if `decimals` is negative
+    fn round_exact(self, r: &BigRational, decimals: i32) -> BigRational {
+        let scale = BigRational::from_integer(BigInt::from(10)).pow(decimals);
+        let scaled = r * &scale;
+
+        let rounded = match self {
+            Self::HalfEven
+                if scaled.fract().abs() == BigRational::new(BigInt::from(1), BigInt::from(2)) =>
+            {
+                let floor = scaled.floor();
+                if floor.to_integer().is_even() {
+                    floor
+                } else {
+                    floor + BigInt::from(1)
+                }
+            }
+            Self::HalfUp | Self::HalfEven => scaled.round(),
+            Self::Up if scaled.is_negative() => scaled.floor(),
+            Self::Up | Self::Ceiling => scaled.ceil(),
+            Self::Down => scaled.trunc(),
+            Self::Floor => scaled.floor(),
+        };
+
+        rounded / scale
+    }
+}
+
+/// Base to write results in
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum Radix {
+    #[default]
+    Decimal,
+    Hex,
+    Octal,
+    Binary,
+}
+
+impl FormatOptions {
+    /// Formats a numeric result, returning a description of the error if it cannot be written this way
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// // With `--precision 2 --thousands ,`
+    /// assert_eq!(format.format(&Value::Float(1234.5)), Ok(String::from("1,234.50")));
+    /// ```
+    fn format(&self, value: &Value) -> Result<String, String> {
+        let formatted = match (self.radix, value) {
+            // Significant digits can round integers, like 12345 to 12000
+            (Radix::Decimal, Value::Int(i)) if self.significant.is_none() => {
+                // Integers are exact, so only the decimal places need to be added
+                match self.precision {
+                    Some(p) if p > 0 && !self.keep_integers => {
+                        format!("{i}.{}", "0".repeat(p as usize))
+                    }
+                    _ => i.to_string(),
+                }
+            }
+            (Radix::Decimal, Value::Int(_) | Value::Float(_)) => {
+                self.format_float(value.as_number().map_err(|e| e.to_string())?)
+            }
+            (radix, Value::Int(i)) => format_radix(&BigInt::from(*i), radix),
+            // Every float in this range is a whole number that fits in an i64
+            (radix, Value::Float(f)) if f.fract() == 0.0 && f.abs() < 2_f64.powi(63) => {
+                format_radix(&BigInt::from(*f as i64), radix)
+            }
+            (radix, Value::Float(f)) => {
+                return Err(format!(
+                    "Result is not a whole number, so it cannot be written in {radix:?}: {f}"
+                ))
+            }
+            (_, v) => return Err(format!("Result is not a number: {v}")),
+        };
+
+        Ok(match self.thousands {
+            Some(sep) => separate_thousands(&formatted, sep),
+            None => formatted,
+        })
+    }
+
+    /// Formats `f` in decimal, rounded to `--precision` or `--significant`
+    fn format_float(&self, f: f64) -> String {
+        let significant_decimals = |f: f64, digits: u32| {
+            let magnitude = if f == 0.0 {
+                0
+            } else {
+                f.abs().log10().floor() as i32
+            };
+            i32::try_from(digits).unwrap_or(i32::MAX) - 1 - magnitude
+        };
+
+        let (rounded, decimals) = match (self.precision, self.significant) {
+            (Some(p), _) => {
+                let decimals = i32::try_from(p).unwrap_or(i32::MAX);
+                (self.rounding.round_to(f, decimals), decimals)
+            }
+            (None, Some(digits)) => {
+                let rounded = self.rounding.round_to(f, significant_decimals(f, digits));
+                // Rounding can add a digit, like 9.99 to 10.0
+                (rounded, significant_decimals(rounded, digits))
+            }
+            (None, None) => return f.to_string(),
+        };
+
+        // Adding zero turns -0 into 0
+        let rounded = rounded + 0.0;
+
+        if decimals <= 0 || (self.keep_integers && rounded.fract() == 0.0) {
+            format!("{rounded:.0}")
+        } else {
+            format!("{rounded:.prec$}", prec = decimals.unsigned_abs() as usize)
+        }
+    }
+
+    /// Formats a result of `--exact`, returning a description of the error if it cannot be written this way
+    ///
+    /// Results that repeat forever (like `1/3`) are rounded to `EXACT_MAX_DECIMALS` decimal places if there is no `--precision` or `--significant`
+    fn format_exact(&self, r: &BigRational) -> Result<String, String> {
+        let formatted = match self.radix {
+            Radix::Decimal => self.format_rational(r),
+            radix if r.is_integer() => format_radix(&r.to_integer(), radix),
+            radix => {
+                return Err(format!(
+                    "Result is not a whole number, so it cannot be written in {radix:?}: {r}"
+                ))
+            }
+        };
+
+        Ok(match self.thousands {
+            Some(sep) => separate_thousands(&formatted, sep),
+            None => formatted,
+        })
+    }
+
+    /// Formats `r` in decimal, rounded to `--precision` or `--significant`
+    fn format_rational(&self, r: &BigRational) -> String {
+        let significant_decimals = |r: &BigRational, digits: u32| {
+            let magnitude = if r.is_zero() { 0 } else { exact::magnitude(r) };
+            i32::try_from(digits)
+                .unwrap_or(i32::MAX)
+                .saturating_sub(1)
+                .saturating_sub(magnitude)
+        };
+
+        let (rounded, decimals, repeats) = match (self.precision, self.significant) {
+            (Some(p), _) => {
+                let decimals = i32::try_from(p).unwrap_or(i32::MAX);
+                (self.rounding.round_exact(r, decimals), decimals, false)
+            }
+            (None, Some(digits)) => {
+                let rounded = self
+                    .rounding
+                    .round_exact(r, significant_decimals(r, digits));
+                // Rounding can add a digit, like 9.99 to 10.0
+                let decimals = significant_decimals(&rounded, digits);
+                (rounded, decimals, false)
+            }
+            (None, None) => exact::exact_decimals(r).map_or_else(
+                || {
+                    (
+                        self.rounding.round_exact(r, EXACT_MAX_DECIMALS),
+                        EXACT_MAX_DECIMALS,
+                        true,
+                    )
+                },
+                |d| (r.clone(), i32::try_from(d).unwrap_or(i32::MAX), false),
+            ),
+        };
+
+        let decimals = if decimals <= 0 || (self.keep_integers && rounded.is_integer()) {
+            0
+        } else {
+            decimals.unsigned_abs()
+        };
+        let formatted = exact::to_decimal(&rounded, decimals);
+
+        if repeats {
+            // Trailing zeros would look like the result is exact
+            formatted
+                .trim_end_matches('0')
+                .trim_end_matches('.')
+                .to_string()
+        } else {
+            formatted
+        }
+    }
+}
+
+/// Formats a whole number in hex, octal, or binary, with a prefix like `0x`
+fn format_radix(n: &BigInt, radix: Radix) -> String {
+    let sign = if n.is_negative() { "-" } else { "" };
+    let (prefix, base) = match radix {
+        Radix::Decimal => return n.to_string(),
+        Radix::Hex => ("0x", 16),
+        Radix::Octal => ("0o", 8),
+        Radix::Binary => ("0b", 2),
+    };
+
+    format!("{sign}{prefix}{}", n.abs().to_str_radix(base))
+}
+
+/// Separates every three digits before the decimal point of a formatted number, like `-1234.5` to `-1,234.5`
+fn separate_thousands(s: &str, separator: char) -> String {
+    let (sign, unsigned) = s.strip_prefix('-').map_or(("", s), |u| ("-", u));
+
+    // Hex, octal, and binary numbers are left alone
+    if unsigned.starts_with("0x") || unsigned.starts_with("0o") || unsigned.starts_with("0b") {
+        return s.to_string();
+    }
+
+    match unsigned.split_once('.') {
+        Some((int, fraction)) => format!("{sign}{}.{fraction}", group_thousands(int, separator)),
+        None => format!("{sign}{}", group_thousands(unsigned, separator)),
+    }
+}
+
+/// Variables shared between every selection, plus built-in variables for the current selection
+///
+/// * `i` - Index of the current selection (starting at 1)
+/// * `prev` - Result of the closest previous selection that evaluated to a number
+/// * `s[N]` - Result of selection `N` (starting at 1), if it has already been evaluated
+struct SelectionContext {
+    /// Variables assigned by any selection
+    shared: HashMapContext,
+    /// Index of the current selection, as a `Value` so it can be borrowed
+    index: Value,
+    /// Results of every selection evaluated so far
+    results: Vec<Value>,
+}
+
+impl SelectionContext {
+    fn new() -> Self {
+        Self {
+            shared: functions::context(),
+            index: Value::Int(1),
+            results: Vec::new(),
+        }
+    }
 
-    let selections = get_selections(None)?;
+    /// Gets the result of selection `n` (starting at 1) from the name `s[N]` was rewritten to
+    fn selection_ref(&self, identifier: &str) -> Option<&Value> {
+        let n = identifier
+            .strip_prefix(SELECTION_REF_PREFIX)?
+            .parse::<usize>()
+            .ok()?;
+        self.results.get(n.checked_sub(1)?)
+    }
 
-    set_selections(selections.iter().map(|s| match eval(s) {
-        Ok(Value::Float(f)) => Cow::Owned(f.to_string()),
-        Ok(Value::Int(f)) => Cow::Owned(f.to_string()),
-        Ok(_) => Cow::Borrowed(""),
-        Err(e) => {
-            eprintln!("Error: {:?}", e);
-            err_count = err_count.saturating_add(1);
-            // Set the selection to empty
-            Cow::Borrowed("")
+    /// Evaluates the next selection, recording its result for `prev` and `s[N]`
+    fn evaluate_next(
+        &mut self,
+        s: &str,
+        selection_ref_regex: &Regex,
+        format: &FormatOptions,
+    ) -> Result<Option<String>, String> {
+        self.index = Value::Int(i64::try_from(self.results.len()).map_or(i64::MAX, |i| i + 1));
+
+        let result = evaluate(s, self, selection_ref_regex, format);
+        self.results.push(
+            result
+                .as_ref()
+                .map_or(Value::Empty, |(_, value)| value.clone()),
+        );
+
+        result.map(|(new_content, _)| new_content)
+    }
+}
+
+impl Context for SelectionContext {
+    fn get_value(&self, identifier: &str) -> Option<&Value> {
+        match identifier {
+            "i" => Some(&self.index),
+            "prev" => self.results.iter().rev().find(|v| v.is_number()),
+            _ => self
+                .selection_ref(identifier)
+                .or_else(|| self.shared.get_value(identifier)),
         }
-    }))?;
+    }
+
+    fn call_function(&self, identifier: &str, argument: &Value) -> EvalexprResult<Value> {
+        self.shared.call_function(identifier, argument)
+    }
+}
+
+impl ContextWithMutableVariables for SelectionContext {
+    fn set_value(&mut self, identifier: String, value: Value) -> EvalexprResult<()> {
+        if identifier == "i" || identifier == "prev" {
+            return Err(EvalexprError::CustomMessage(format!(
+                "'{identifier}' is a built-in variable and cannot be assigned"
+            )));
+        }
+
+        self.shared.set_value(identifier, value)
+    }
+}
+
+pub fn math_eval(options: &Options) -> Result<String, KakError> {
+    let selection_ref_regex = Regex::new(SELECTION_REF_REGEX)
+        .map_err(|e| KakError::Custom(format!("Could not compile selection regex: {e}")))?;
+    let mut context = SelectionContext::new();
+    let mut exact_context = ExactContext::default();
+    let mut errors = Vec::new();
+
+    let selections = get_selections_with_desc(None)?;
+
+    let new_selections: Vec<Cow<'_, str>> = selections
+        .iter()
+        .map(|s| {
+            let result = if options.exact {
+                exact_context
+                    .evaluate_next(&s.content)
+                    .and_then(|r| r.map(|r| options.format.format_exact(&r)).transpose())
+            } else {
+                context.evaluate_next(&s.content, &selection_ref_regex, &options.format)
+            };
+
+            match result {
+                Ok(new_content) => {
+                    // Assignments evaluate to nothing, so the assignment itself is kept
+                    new_content.map_or(Cow::Borrowed(s.content.as_str()), Cow::Owned)
+                }
+                Err(error) => {
+                    errors.push(EvalError {
+                        desc: s.desc,
+                        content: &s.content,
+                        error,
+                    });
+                    Cow::Borrowed(options.errors.replacement(&s.content))
+                }
+            }
+        })
+        .collect();
 
-    Ok(if err_count == 0 {
+    // Abort before anything is written if the user asked for it
+    let error_details = options.errors.report(&errors)?;
+
+    set_selections(new_selections.iter())?;
+
+    let msg = if errors.is_empty() {
         format!("Processed {} selections", selections.len())
     } else {
         format!(
             "Processed {} selections ({} error{})",
-            selections.len().saturating_sub(err_count),
-            err_count,
-            if err_count == 1 { "" } else { "s" }
+            selections.len().saturating_sub(errors.len()),
+            errors.len(),
+            if errors.len() == 1 { "" } else { "s" }
         )
-    })
+    };
+
+    // The failed selections are shown as the message's details
+    match error_details {
+        Some(details) => Err(KakError::CustomDetails(msg, details)),
+        None => Ok(msg),
+    }
+}
+
+/// Evaluates a single selection, returning the new content (if it should change) and the value other selections see
+///
+/// Returns a description of the error if it fails
+fn evaluate(
+    s: &str,
+    context: &mut SelectionContext,
+    selection_ref_regex: &Regex,
+    format: &FormatOptions,
+) -> Result<(Option<String>, Value), String> {
+    // Date arithmetic like `2023-01-01 + 3d` is not understood by evalexpr
+    if let Some(d) = date::eval(s) {
+        return Ok((Some(d.clone()), Value::String(d)));
+    }
+
+    // Units like `3 MiB + 512 KiB` are not understood by evalexpr either
+    if let Some(quantity) = units::eval(s, format.to) {
+        let quantity = quantity?;
+        let value = Value::Float(quantity.value);
+        let formatted = format.format(&value)?;
+
+        return Ok((
+            Some(match quantity.unit {
+                Some(unit) => format!("{formatted} {}", unit.symbol),
+                None => formatted,
+            }),
+            value,
+        ));
+    }
+
+    let expr = selection_ref_regex.replace_all(s, format!("{SELECTION_REF_PREFIX}$1"));
+    let expr = functions::expand_radix_literals(&expr);
+
+    match eval_with_context_mut(&expr, context) {
+        Ok(v @ (Value::Float(_) | Value::Int(_))) => Ok((Some(format.format(&v)?), v)),
+        // Functions like `hex` and `pad` return strings
+        Ok(Value::String(text)) => Ok((Some(text.clone()), Value::String(text))),
+        Ok(Value::Empty) => Ok((None, Value::Empty)),
+        Ok(v) => Err(format!("Result is not a number: {v}")),
+        Err(EvalexprError::VariableIdentifierNotFound(identifier))
+            if identifier.starts_with(SELECTION_REF_PREFIX) =>
+        {
+            Err(format!(
+                "s[{}] has not been evaluated yet",
+                &identifier[SELECTION_REF_PREFIX.len()..]
+            ))
+        }
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Evaluates each expression in order with one context, like `math_eval` does
+    fn evaluate_all(exprs: &[&str]) -> Vec<Result<Option<String>, String>> {
+        let regex = Regex::new(SELECTION_REF_REGEX).unwrap();
+        let mut context = SelectionContext::new();
+
+        exprs
+            .iter()
+            .map(|s| context.evaluate_next(s, &regex, &FormatOptions::default()))
+            .collect()
+    }
+
+    #[test]
+    fn test_shared_context() {
+        assert_eq!(
+            evaluate_all(&["x = 5", "x * 2", "prev + 1", "s[2] + s[ 3 ]", "i"]),
+            vec![
+                Ok(None),
+                Ok(Some(String::from("10"))),
+                Ok(Some(String::from("11"))),
+                Ok(Some(String::from("21"))),
+                Ok(Some(String::from("5"))),
+            ]
+        );
+        assert_eq!(
+            evaluate_all(&["s[2]", "1"]),
+            vec![
+                Err(String::from("s[2] has not been evaluated yet")),
+                Ok(Some(String::from("1"))),
+            ]
+        );
+        assert!(evaluate_all(&["prev = 1"])[0].is_err());
+        assert_eq!(
+            evaluate_all(&["x = 0b1111", "hex(x + 0x1)"]),
+            vec![Ok(None), Ok(Some(String::from("0x10")))]
+        );
+    }
+
+    #[test]
+    fn test_format() {
+        let o = FormatOptions::default();
+        let with = |f: &dyn Fn(&mut FormatOptions), v: Value| {
+            let mut o = o.clone();
+            f(&mut o);
+            o.format(&v)
+        };
+        let ok = |s: &str| Ok(String::from(s));
+
+        assert_eq!(
+            o.format(&Value::Float(0.1 + 0.2)),
+            ok("0.30000000000000004")
+        );
+        assert_eq!(
+            with(&|o| o.precision = Some(2), Value::Float(0.1 + 0.2)),
+            ok("0.30")
+        );
+        assert_eq!(with(&|o| o.precision = Some(2), Value::Int(3)), ok("3.00"));
+        assert_eq!(with(&|o| o.precision = Some(0), Value::Float(2.5)), ok("3"));
+        assert_eq!(
+            with(&|o| o.precision = Some(2), Value::Float(-0.001)),
+            ok("0.00")
+        );
+        assert_eq!(
+            with(
+                &|o| {
+                    o.precision = Some(0);
+                    o.rounding = Rounding::HalfEven;
+                },
+                Value::Float(2.5)
+            ),
+            ok("2")
+        );
+        assert_eq!(
+            with(
+                &|o| {
+                    o.precision = Some(1);
+                    o.rounding = Rounding::Floor;
+                },
+                Value::Float(-1.25)
+            ),
+            ok("-1.3")
+        );
+        assert_eq!(
+            with(
+                &|o| {
+                    o.precision = Some(2);
+                    o.keep_integers = true;
+                },
+                Value::Float(4.0)
+            ),
+            ok("4")
+        );
+        assert_eq!(
+            with(&|o| o.significant = Some(3), Value::Float(1.23456)),
+            ok("1.23")
+        );
+        assert_eq!(
+            with(&|o| o.significant = Some(2), Value::Float(9.99)),
+            ok("10")
+        );
+        assert_eq!(
+            with(&|o| o.significant = Some(2), Value::Int(12345)),
+            ok("12000")
+        );
+        assert_eq!(
+            with(
+                &|o| {
+                    o.significant = Some(2);
+                    o.keep_integers = true;
+                },
+                Value::Int(12345)
+            ),
+            ok("12000")
+        );
+        assert_eq!(
+            with(&|o| o.significant = Some(2), Value::Float(0.012_345)),
+            ok("0.012")
+        );
+        assert_eq!(
+            with(
+                &|o| {
+                    o.precision = Some(2);
+                    o.thousands = Some(',');
+                },
+                Value::Float(-1_234_567.891)
+            ),
+            ok("-1,234,567.89")
+        );
+        assert_eq!(with(&|o| o.radix = Radix::Hex, Value::Int(255)), ok("0xff"));
+        assert_eq!(
+            with(&|o| o.radix = Radix::Binary, Value::Float(-5.0)),
+            ok("-0b101")
+        );
+        assert_eq!(with(&|o| o.radix = Radix::Octal, Value::Int(8)), ok("0o10"));
+        assert!(with(&|o| o.radix = Radix::Hex, Value::Float(1.5)).is_err());
+        assert!(o.format(&Value::Boolean(true)).is_err());
+    }
+
+    #[test]
+    fn test_format_exact() {
+        let r = |n: i64, d: i64| BigRational::new(BigInt::from(n), BigInt::from(d));
+        let o = FormatOptions::default();
+        let with = |f: &dyn Fn(&mut FormatOptions), r: BigRational| {
+            let mut o = o.clone();
+            f(&mut o);
+            o.format_exact(&r)
+        };
+        let ok = |s: &str| Ok(String::from(s));
+
+        assert_eq!(o.format_exact(&r(3, 10)), ok("0.3"));
+        assert_eq!(o.format_exact(&r(-5, 1)), ok("-5"));
+        assert_eq!(o.format_exact(&r(1, 3)), ok("0.33333333333333333333"));
+        assert_eq!(o.format_exact(&r(2, 3)), ok("0.66666666666666666667"));
+        assert_eq!(with(&|o| o.precision = Some(2), r(1, 3)), ok("0.33"));
+        assert_eq!(with(&|o| o.precision = Some(2), r(5, 1)), ok("5.00"));
+        assert_eq!(
+            with(
+                &|o| {
+                    o.precision = Some(0);
+                    o.rounding = Rounding::HalfEven;
+                },
+                r(5, 2)
+            ),
+            ok("2")
+        );
+        assert_eq!(
+            with(
+                &|o| {
+                    o.precision = Some(0);
+                    o.rounding = Rounding::HalfEven;
+                },
+                r(-7, 2)
+            ),
+            ok("-4")
+        );
+        assert_eq!(with(&|o| o.precision = Some(0), r(-5, 2)), ok("-3"));
+        assert_eq!(with(&|o| o.significant = Some(2), r(12345, 1)), ok("12000"));
+        assert_eq!(with(&|o| o.significant = Some(2), r(999, 100)), ok("10"));
+        assert_eq!(
+            with(&|o| o.significant = Some(2), r(12345, 1_000_000)),
+            ok("0.012")
+        );
+        assert_eq!(
+            with(&|o| o.thousands = Some(','), r(123_456_789, 100)),
+            ok("1,234,567.89")
+        );
+        assert_eq!(with(&|o| o.radix = Radix::Hex, r(-255, 1)), ok("-0xff"));
+        assert!(with(&|o| o.radix = Radix::Hex, r(1, 2)).is_err());
+    }
 }
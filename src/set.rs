@@ -1,11 +1,10 @@
-// use crate::utils;
+use crate::utils::KeyOptions;
 use kakplugin::{
     get_register_selections, get_selections, get_selections_with_desc, set_selections_desc,
     types::Register, KakError,
 };
 use linked_hash_map::LinkedHashMap;
 use linked_hash_set::LinkedHashSet;
-use regex::Regex;
 use std::{borrow::Cow, io::Write, str::FromStr};
 
 const KAK_BUFFER_NAME: &str = "*kakplugin-set*";
@@ -19,16 +18,8 @@ pub struct Options {
     )]
     args: Vec<String>,
 
-    #[clap(short, long, help = "Trim each selection before doing set operations")]
-    skip_whitespace: bool,
-    // #[clap(short, long)]
-    #[clap(skip)]
-    regex: Option<Regex>,
-    // #[clap(short, long)]
-    #[clap(skip)]
-    ignore_case: bool,
-    // #[clap(short = 'S', long)]
-    // no_skip_whitespace: bool,
+    #[clap(flatten)]
+    key: KeyOptions,
 }
 
 #[derive(Clone, Debug)]
@@ -171,12 +162,7 @@ fn reduce_selections<'sel, 'a>(
         // Does not matter if the operation was - or &
         // Since key_set_operation_result contains elements that should be in the resulting set,
         // we can just use contains here
-        let key = crate::utils::get_key(
-            &swd.content,
-            !options.skip_whitespace,
-            options.regex.as_ref(),
-            options.ignore_case,
-        );
+        let key = crate::utils::get_key(&swd.content, &options.key);
 
         if key_set_operation_result.contains(key.as_ref()) {
             Some(swd.desc)
@@ -295,12 +281,7 @@ fn to_ordered_counts<'sel>(
     let mut ret = LinkedHashMap::new();
 
     for i in selections {
-        let key = crate::utils::get_key(
-            &i,
-            !options.skip_whitespace,
-            options.regex.as_ref(),
-            options.ignore_case,
-        );
+        let key = crate::utils::get_key(i, &options.key);
 
         if key.is_empty() {
             // We don't want to even pretend to look at empty keys
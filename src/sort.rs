@@ -1,40 +1,46 @@
+use crate::utils::{self, KeyOptions};
 use alphanumeric_sort::compare_str;
-use clap::ArgAction;
-use kakplugin::{self, get_selections_with_desc, open_command_fifo, KakError, SelectionWithDesc};
+use kakplugin::{
+    self, get_selections_desc, get_selections_with_desc, set_selections, set_selections_desc,
+    KakError, SelectionWithDesc,
+};
 use regex::Regex;
-use std::{borrow::Cow, cmp::Ordering, io::Write};
+use std::{borrow::Cow, cmp::Ordering, collections::BTreeSet};
 
 #[derive(clap::Args, Debug)]
 pub struct Options {
-    #[clap(index = 1, help = "Optional regex comparison key")]
-    regex: Option<Regex>,
+    #[clap(
+        index = 1,
+        value_name = "REGEX",
+        conflicts_with = "regex",
+        help = "Optional regex comparison key"
+    )]
+    positional_regex: Option<Regex>,
     #[clap(
         short = 's',
         long,
         help = "Optional register for using subselection matching"
     )]
     subselections_register: Option<char>,
-    // TODO: Can we invert a boolean? This name is terrible
-    // #[clap(short = 'S', long, value_parser = invert_bool, default_value_t, help = "Do not treat trimmed value of selections when sorting")]
-    #[clap(short = 'S', long, action = ArgAction::SetFalse, default_value_t, help = "Do not treat trimmed value of selections when sorting")]
-    no_skip_whitespace: bool,
     #[clap(short = 'L', long, help = "Do not sort numbers lexicographically")]
     no_lexicographic_sort: bool,
     #[clap(short, long, help = "Reverse sorting")]
     reverse: bool,
-    #[clap(short, long, help = "Ignore case when sorting")]
-    ignore_case: bool,
+    #[clap(
+        short,
+        long,
+        help = "Delete and deselect selections with the same key as an earlier sorted selection"
+    )]
+    unique: bool,
+    #[clap(
+        long,
+        help = "Sort using unicode collation, so accented letters sort next to their base letters"
+    )]
+    collate: bool,
+    #[clap(flatten)]
+    key: KeyOptions,
 }
 
-// fn invert_bool(s: &str) -> Result<bool, &'static str> {
-//     // Invert the boolean
-//     match s {
-//         "false" => Ok(true),
-//         "true" => Ok(false),
-//         _ => Err("Unparsable boolean value"),
-//     }
-// }
-
 struct SortableSelection<'a> {
     /// The content of the selection
     selection: &'a SelectionWithDesc,
@@ -105,18 +111,12 @@ struct SortableSelection<'a> {
 
 fn to_sortable_selection<'a, 'b>(
     selection: &'a SelectionWithDesc,
-    options: &'b Options,
+    key_options: &'b KeyOptions,
 ) -> SortableSelection<'a> {
     SortableSelection {
         selection,
         // TODO: Properly use Cow
-        content_comparison: crate::utils::get_key(
-            &selection.content,
-            !options.no_skip_whitespace,
-            options.regex.as_ref(),
-            options.ignore_case,
-        )
-        .into(),
+        content_comparison: utils::get_key(&selection.content, key_options),
         subselections: vec![],
     }
 }
@@ -134,8 +134,9 @@ pub fn sort(options: &Options) -> Result<String, KakError> {
         })
         .transpose()?;
     let selections = get_selections_with_desc(None)?;
+    let key_options = options.key.with_regex(options.positional_regex.as_ref());
 
-    let mut zipped: Vec<SortableSelection<'_>> = match (&options.regex, &subselections) {
+    let mut zipped: Vec<SortableSelection<'_>> = match (&key_options.regex, &subselections) {
         (Some(_), Some(_)) => {
             return Err(KakError::Custom(
                 "Cannot pass regex and subselections register".to_string(),
@@ -145,14 +146,14 @@ pub fn sort(options: &Options) -> Result<String, KakError> {
             // Do a regular sort on the content
             selections
                 .iter()
-                .map(|s| to_sortable_selection(s, options))
+                .map(|s| to_sortable_selection(s, &key_options))
                 .collect()
         }
         (Some(_regex), None) => {
             // Sort based on the regular expression
             selections
                 .iter()
-                .map(|s| to_sortable_selection(s, options))
+                .map(|s| to_sortable_selection(s, &key_options))
                 .collect()
 
             // TODO: Figure out if this is fine
@@ -182,14 +183,20 @@ pub fn sort(options: &Options) -> Result<String, KakError> {
         }
     };
 
+    let compare = |a: &str, b: &str| {
+        if options.collate {
+            utils::collate(a, b, !options.no_lexicographic_sort)
+        } else if options.no_lexicographic_sort {
+            a.cmp(b)
+        } else {
+            compare_str(a, b)
+        }
+    };
+
     zipped.sort_by(|a, b| {
         // First, try sorting by subselection. This won't iterate anything if either is None (regex and default mode)
         for (a_subselection, b_subselection) in a.subselections.iter().zip(b.subselections.iter()) {
-            let comparison = if options.no_lexicographic_sort {
-                a_subselection.cmp(b_subselection)
-            } else {
-                compare_str(&a_subselection, &b_subselection)
-            };
+            let comparison = compare(a_subselection, b_subselection);
 
             // If the comparison is not equal, stop here
             if comparison != Ordering::Equal {
@@ -198,29 +205,54 @@ pub fn sort(options: &Options) -> Result<String, KakError> {
         }
 
         // Otherwise, default to comparing the content
-        if options.no_lexicographic_sort {
-            a.content_comparison.cmp(&b.content_comparison)
-        } else {
-            compare_str(&a.content_comparison, &b.content_comparison)
-        }
+        compare(&a.content_comparison, &b.content_comparison)
     });
 
-    let mut f = open_command_fifo()?;
-
-    write!(f, "reg '\"'")?;
-
     let iter: Box<dyn Iterator<Item = _>> = if options.reverse {
         Box::new(zipped.iter().rev())
     } else {
         Box::new(zipped.iter())
     };
 
-    for i in iter {
-        let new_selection = i.selection.content.replace('\'', "''");
-        write!(f, " '{}'", new_selection)?;
+    // Pair each sorted selection with whether it should be kept
+    // Like uniq, a BTreeSet of hashes is used so only the first selection with each key is kept
+    let mut seen = BTreeSet::new();
+    let sorted: Vec<(&SortableSelection<'_>, bool)> = iter
+        .map(|s| {
+            (
+                s,
+                !options.unique || seen.insert(utils::get_hash(&s.selection.content, &key_options)),
+            )
+        })
+        .collect();
+
+    // Duplicates are emptied so they can be deselected below
+    set_selections(sorted.iter().map(|(i, keep)| {
+        if *keep {
+            i.selection.content.as_str()
+        } else {
+            ""
+        }
+    }))?;
+
+    let num_removed = sorted.iter().filter(|(_, keep)| !keep).count();
+
+    if num_removed == 0 {
+        return Ok(format!("Sorted {} selections", zipped.len()));
     }
-    write!(f, " ; exec R;")?;
-    f.flush()?;
 
-    Ok(format!("Sorted {} selections", zipped.len()))
+    // Refresh selections_desc because positions have changed, and deselect the emptied duplicates
+    set_selections_desc(
+        get_selections_desc::<&str>(None)?
+            .iter()
+            .zip(sorted.iter())
+            .filter_map(|(sd, (_, keep))| keep.then_some(sd)),
+    )?;
+
+    Ok(format!(
+        "Sorted {} selections ({} duplicate{} removed)",
+        zipped.len().saturating_sub(num_removed),
+        num_removed,
+        if num_removed == 1 { "" } else { "s" }
+    ))
 }
@@ -0,0 +1,177 @@
+use kakplugin::{get_selections_with_desc, types::Register, KakError};
+use std::io::Write;
+
+#[derive(clap::Args, Debug)]
+pub struct Options {
+    #[clap(
+        short,
+        long,
+        help = "Store count, sum, mean, median, min, max, and stddev in this register (in that order)"
+    )]
+    register: Option<Register>,
+    #[clap(
+        short,
+        long,
+        help = "Append the statistics on a new line after the last selection"
+    )]
+    append: bool,
+}
+
+/// Statistics over the numeric selections
+#[derive(Debug, PartialEq)]
+struct Stats {
+    count: usize,
+    sum: f64,
+    mean: f64,
+    median: f64,
+    min: f64,
+    max: f64,
+    /// Sample standard deviation, or 0 if there is only one value
+    stddev: f64,
+}
+
+impl Stats {
+    /// Calculates statistics over `values`, returning `None` if there are no values (or too many to count)
+    fn new(values: &mut [f64]) -> Option<Self> {
+        values.sort_by(f64::total_cmp);
+
+        let count = values.len();
+        let (min, max) = (*values.first()?, *values.last()?);
+        let n = f64::from(u32::try_from(count).ok()?);
+        let sum: f64 = values.iter().sum();
+        let mean = sum / n;
+        let median = if count % 2 == 1 {
+            values[count / 2]
+        } else {
+            // Halve first so large values do not overflow
+            values[count / 2 - 1] / 2.0 + values[count / 2] / 2.0
+        };
+        let stddev = if count < 2 {
+            0.0
+        } else {
+            (values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1.0)).sqrt()
+        };
+
+        Some(Self {
+            count,
+            sum,
+            mean,
+            median,
+            min,
+            max,
+            stddev,
+        })
+    }
+
+    /// Each statistic with its name, in the order they are stored in a register
+    fn fields(&self) -> [(&'static str, String); 7] {
+        [
+            ("count", self.count.to_string()),
+            ("sum", self.sum.to_string()),
+            ("mean", self.mean.to_string()),
+            ("median", self.median.to_string()),
+            ("min", self.min.to_string()),
+            ("max", self.max.to_string()),
+            ("stddev", self.stddev.to_string()),
+        ]
+    }
+
+    /// All statistics on one line, like `count: 3, sum: 6, ...`
+    fn summary(&self) -> String {
+        self.fields()
+            .iter()
+            .map(|(name, value)| format!("{name}: {value}"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+pub fn stats(options: &Options) -> Result<String, KakError> {
+    let selections = get_selections_with_desc(None)?;
+
+    // Selections that are not numbers are skipped
+    let mut values = selections
+        .iter()
+        .filter_map(|s| s.content.trim().replace('_', "").parse::<f64>().ok())
+        .filter(|v| v.is_finite())
+        .collect::<Vec<_>>();
+    let skipped = selections.len().saturating_sub(values.len());
+
+    let stats =
+        Stats::new(&mut values).ok_or(KakError::CustomStatic("No selections contain a number"))?;
+    let summary = stats.summary();
+
+    if let Some(register) = &options.register {
+        kakplugin::cmd(format!(
+            "set-register {} {}",
+            register.kak_expanded(),
+            stats
+                .fields()
+                .iter()
+                .map(|(_, value)| format!("'{value}'"))
+                .collect::<Vec<_>>()
+                .join(" ")
+        ))?;
+    }
+
+    if options.append {
+        // Selections are in document order, so the last one is at the bottom
+        let last = selections.last().ok_or(KakError::SetEmptySelections)?;
+        let mut f = kakplugin::open_command_fifo()?;
+
+        // Line selections already end in a newline, so the summary goes on the next line by itself
+        let pasted = if last.content.ends_with('\n') {
+            format!("{summary}\n")
+        } else {
+            format!("\n{summary}")
+        };
+
+        write!(
+            f,
+            r#"evaluate-commands -draft -save-regs '"' %{{
+                select {};
+                set-register '"' '{}';
+                execute-keys p;
+            }}"#,
+            last.desc,
+            kakplugin::escape(&pasted)
+        )?;
+
+        f.flush()?;
+    }
+
+    Ok(if skipped == 0 {
+        summary
+    } else {
+        format!(
+            "{summary} ({skipped} non-numeric selection{} skipped)",
+            if skipped == 1 { "" } else { "s" }
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        assert_eq!(
+            Stats::new(&mut [4.0, 1.0, 3.0, 2.0]),
+            Some(Stats {
+                count: 4,
+                sum: 10.0,
+                mean: 2.5,
+                median: 2.5,
+                min: 1.0,
+                max: 4.0,
+                stddev: (5.0_f64 / 3.0).sqrt(),
+            })
+        );
+        assert_eq!(
+            Stats::new(&mut [-2.0]).map(|s| s.summary()).as_deref(),
+            Some("count: 1, sum: -2, mean: -2, median: -2, min: -2, max: -2, stddev: 0")
+        );
+        assert_eq!(Stats::new(&mut []), None);
+    }
+}
@@ -1,72 +1,539 @@
-use crate::utils;
+use crate::utils::{self, KeyOptions};
 use kakplugin::{
-    get_selections_desc, get_selections_with_desc, set_selections, set_selections_desc, KakError,
-    SelectionWithDesc,
+    get_selections_desc, get_selections_with_desc, set_selections, set_selections_desc,
+    types::Register, KakError, SelectionWithDesc,
 };
 use regex::Regex;
-use std::collections::BTreeSet;
+use std::{
+    borrow::Cow,
+    collections::{btree_map::Entry, BTreeMap},
+    io::Write,
+};
+
+const KAK_BUFFER_NAME: &str = "*kakplugin-uniq*";
 
 #[derive(clap::Args, Debug)]
 pub struct Options {
-    #[clap(index = 1, help = "Optional regex to compare unique elements")]
-    regex: Option<Regex>,
-    #[clap(short, long, help = "Ignore the case when comparing")]
-    ignore_case: bool,
-    // TODO: Can we invert a boolean? This name is terrible
-    #[clap(short = 'S', long, help = "Do not skip whitespace when comparing")]
-    no_skip_whitespace: bool,
+    #[clap(
+        index = 1,
+        value_name = "REGEX",
+        conflicts_with = "regex",
+        help = "Optional regex to compare unique elements"
+    )]
+    positional_regex: Option<Regex>,
+    #[clap(flatten)]
+    key: KeyOptions,
+    #[clap(
+        short = 'd',
+        long,
+        conflicts_with = "unique",
+        help = "Only keep selections whose key occurs more than once"
+    )]
+    repeated: bool,
+    #[clap(
+        short,
+        long,
+        help = "Only keep selections whose key occurs exactly once"
+    )]
+    unique: bool,
+    #[clap(
+        short,
+        long,
+        help = "Keep the last occurrence of each key instead of the first"
+    )]
+    last: bool,
+    #[clap(
+        short,
+        long,
+        help = "Prefix each kept selection with the number of times its key occurs"
+    )]
+    count: bool,
+    #[clap(
+        short = 'C',
+        long,
+        help = "Store the number of times each kept selection's key occurs in this register instead of prefixing it"
+    )]
+    count_register: Option<Register>,
+    #[clap(
+        short,
+        long,
+        help = "Only deselect the removed selections instead of deleting their text"
+    )]
+    filter: bool,
+    #[clap(
+        long,
+        conflicts_with = "min_similarity",
+        help = "Treat keys within this Levenshtein edit distance as duplicates"
+    )]
+    max_distance: Option<usize>,
+    #[clap(
+        long,
+        value_parser = parse_ratio,
+        help = "Treat keys with at least this Jaro-Winkler similarity (0 to 1) as duplicates"
+    )]
+    min_similarity: Option<f64>,
+    #[clap(
+        long,
+        help = "Compare keys after removing punctuation and collapsing whitespace"
+    )]
+    loose: bool,
+    #[clap(
+        long,
+        help = "Write each group of duplicates to a scratch buffer instead of changing selections"
+    )]
+    report: bool,
+    #[clap(
+        long,
+        help = "Only treat selections as duplicates if they are next to each other, like GNU uniq"
+    )]
+    adjacent: bool,
+}
+
+/// How close two keys must be to be treated as duplicates
+#[derive(Debug, Clone, Copy)]
+enum Similarity {
+    /// Maximum Levenshtein edit distance
+    Distance(usize),
+    /// Minimum Jaro-Winkler similarity
+    Ratio(f64),
+}
+
+impl Similarity {
+    fn from_options(options: &Options) -> Option<Self> {
+        options
+            .max_distance
+            .map(Self::Distance)
+            .or_else(|| options.min_similarity.map(Self::Ratio))
+    }
+
+    fn is_similar(self, a: &str, b: &str) -> bool {
+        match self {
+            Self::Distance(distance) => strsim::levenshtein(a, b) <= distance,
+            Self::Ratio(ratio) => strsim::jaro_winkler(a, b) >= ratio,
+        }
+    }
 }
+
+fn parse_ratio(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(r) if (0.0..=1.0).contains(&r) => Ok(r),
+        Ok(_) => Err(String::from("Similarity must be between 0 and 1")),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
 pub fn uniq(options: &Options) -> Result<String, KakError> {
+    if options.filter && options.count && options.count_register.is_none() {
+        return Err(KakError::CustomStatic(
+            "Counts cannot be prefixed when filtering. Use --count-register instead",
+        ));
+    }
+
     let mut selections = get_selections_with_desc(None)?;
     // Sort selections so the first element is the unique one, not an arbitrary one based on primary selection
     selections.sort_by_key(|s| s.desc.sort());
 
-    // Set the new selection types
-    let new_selections: Vec<Option<SelectionWithDesc>> = selections
-        .into_iter()
-        // Create a BTreeSet of hashes of selections. This way, string content is not stored, but uniqueness can be determined
-        .scan(BTreeSet::new(), |state, sd| {
-            let hash = utils::get_hash(
-                &sd.content,
-                !options.no_skip_whitespace,
-                options.regex.as_ref(),
-                options.ignore_case,
-            );
-
-            // Try inserting to the hash
-            if state.insert(hash) {
-                // True if this is a string we haven't seen before
-                Some(Some(sd))
+    let groups = groups(&selections, options);
+
+    // The frequency of the key of every kept selection, or None if the selection should be removed
+    let kept_counts = kept_counts(&groups, options);
+
+    let old_count = kept_counts.len();
+    let new_count = kept_counts.iter().flatten().count();
+
+    if options.report {
+        print_groups(
+            &selections
+                .iter()
+                .map(|s| s.content.as_str())
+                .collect::<Vec<_>>(),
+            &groups,
+            &kept_counts,
+        )?;
+
+        return Ok(format!(
+            "Reported {new_count} groups out of {old_count} selections"
+        ));
+    }
+
+    if new_count == 0 {
+        // Do not empty every selection, since there would be nothing left to select
+        return Err(KakError::CustomStatic("No selections would be kept"));
+    }
+
+    if options.filter {
+        // The text is untouched, so the selections_desc we already have are still valid
+        set_selections_desc(
+            selections
+                .iter()
+                .zip(kept_counts.iter())
+                .filter_map(|(s, count)| count.map(|_| &s.desc)),
+        )?;
+    } else {
+        set_selections(
+            selections
+                .iter()
+                .zip(kept_counts.iter())
+                .map(|(s, count)| match count {
+                    Some(count) if options.count && options.count_register.is_none() => {
+                        Cow::Owned(format!("{count} {}", s.content))
+                    }
+                    Some(_) => Cow::Borrowed(s.content.as_str()),
+                    None => Cow::Borrowed(""),
+                }),
+        )?;
+
+        // Deselect all `None` strings (all rows that have been seen before)
+        let mut new_selections_desc = get_selections_desc::<&str>(None)?;
+        new_selections_desc.sort();
+        set_selections_desc(
+            // Refresh seelections_desc because positions have changed
+            new_selections_desc
+                .iter()
+                .zip(kept_counts.iter())
+                // If the string was emptied (None), then do not set `sd`
+                .filter_map(|(sd, count)| count.map(|_| sd)),
+        )?;
+    }
+
+    if let Some(register) = &options.count_register {
+        // Register values line up with the selections that are left
+        kakplugin::cmd(format!(
+            "set-register {} {}",
+            register.kak_expanded(),
+            kept_counts
+                .iter()
+                .flatten()
+                .map(|count| format!("'{count}'"))
+                .collect::<Vec<_>>()
+                .join(" ")
+        ))?;
+    }
+
+    Ok(format!(
+        "{} {} selections out of {}",
+        new_count,
+        if options.repeated {
+            "repeated"
+        } else {
+            "unique"
+        },
+        old_count
+    ))
+}
+
+/// Assigns every selection to a group of duplicates based on the options
+///
+/// The group of a selection is the index of the first selection in its group
+fn groups(selections: &[SelectionWithDesc], options: &Options) -> Vec<usize> {
+    let key_options = options.key.with_regex(options.positional_regex.as_ref());
+    let keys: Vec<Cow<'_, str>> = selections
+        .iter()
+        .map(|sd| {
+            let key = utils::get_key(&sd.content, &key_options);
+
+            if options.loose {
+                Cow::Owned(loosen(&key))
             } else {
-                // Nothing was inserted because we already saw this string
-                // Return Some(None) so the iterator can continue
-                Some(None)
+                key
             }
         })
         .collect();
 
-    set_selections(new_selections.iter().map(|i| match i {
-        Some(s) => &s.content,
-        None => "",
-    }))?;
-
-    // Deselect all `None` strings (all rows that have been seen before)
-    let mut new_selections_desc = get_selections_desc::<&str>(None)?;
-    new_selections_desc.sort();
-    set_selections_desc(
-        // Refresh seelections_desc because positions have changed
-        new_selections_desc
+    let similarity = Similarity::from_options(options);
+
+    if options.adjacent {
+        adjacent_groups(&keys, similarity)
+    } else {
+        similarity.map_or_else(
+            || exact_groups(keys.iter()),
+            |similarity| similar_groups(&keys, similarity),
+        )
+    }
+}
+
+/// Assigns every selection to a group of selections with the same key
+///
+/// The group of a selection is the index of the first selection with the same key
+fn exact_groups<I, K>(keys: I) -> Vec<usize>
+where
+    I: IntoIterator<Item = K>,
+    K: Ord,
+{
+    let mut first_seen = BTreeMap::new();
+
+    keys.into_iter()
+        .enumerate()
+        .map(|(idx, key)| match first_seen.entry(key) {
+            Entry::Occupied(o) => *o.get(),
+            Entry::Vacant(v) => *v.insert(idx),
+        })
+        .collect()
+}
+
+/// Assigns every selection to a group of selections with similar keys
+///
+/// Each key is compared against the first key of every group so far, and joins the first group it is similar to.
+/// The group of a selection is the index of the first selection in the group
+fn similar_groups<K>(keys: &[K], similarity: Similarity) -> Vec<usize>
+where
+    K: AsRef<str>,
+{
+    let mut representatives: Vec<usize> = vec![];
+
+    keys.iter()
+        .enumerate()
+        .map(|(idx, key)| {
+            representatives
+                .iter()
+                .copied()
+                .find(|r| similarity.is_similar(keys[*r].as_ref(), key.as_ref()))
+                .unwrap_or_else(|| {
+                    representatives.push(idx);
+                    idx
+                })
+        })
+        .collect()
+}
+
+/// Assigns every selection to a run of adjacent selections with the same (or similar) key
+///
+/// Each key is only compared against the key before it, so the size of a group is the length of its run.
+/// The group of a selection is the index of the first selection in the run
+fn adjacent_groups<K>(keys: &[K], similarity: Option<Similarity>) -> Vec<usize>
+where
+    K: AsRef<str>,
+{
+    let mut run_start = 0;
+
+    keys.iter()
+        .enumerate()
+        .map(|(idx, key)| {
+            if let Some(previous) = idx.checked_sub(1).map(|i| keys[i].as_ref()) {
+                let is_duplicate = similarity.map_or_else(
+                    || previous == key.as_ref(),
+                    |similarity| similarity.is_similar(previous, key.as_ref()),
+                );
+
+                if !is_duplicate {
+                    run_start = idx;
+                }
+            }
+
+            run_start
+        })
+        .collect()
+}
+
+/// Removes punctuation and collapses runs of whitespace into a single space
+///
+/// # Examples
+///
+/// ```
+/// assert_eq!(loosen("  Hello,   world!\n"), "Hello world");
+/// ```
+fn loosen(s: &str) -> String {
+    s.split_whitespace()
+        .map(|word| {
+            word.chars()
+                .filter(|c| !c.is_ascii_punctuation() && !is_punctuation(*c))
+                .collect::<String>()
+        })
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Non-ascii punctuation that commonly shows up in text
+const fn is_punctuation(c: char) -> bool {
+    matches!(
+        c,
+        '\u{2010}'..='\u{2027}' | '\u{2030}'..='\u{205e}' | '\u{3001}'..='\u{3003}' | '¡' | '¿' | '«' | '»'
+    )
+}
+
+/// Writes every kept group and its members to a new kak buffer
+///
+/// Each group starts with its size and the content of its kept selection. Other members are on the following lines, indented by a tab
+fn print_groups(
+    selections: &[&str],
+    groups: &[usize],
+    kept_counts: &[Option<usize>],
+) -> Result<(), KakError> {
+    let mut f = kakplugin::open_command_fifo()?;
+
+    write!(
+        f,
+        r#"evaluate-commands -save-regs '"' %{{
+                set-register '"'"#
+    )?;
+
+    for (idx, count) in kept_counts.iter().enumerate() {
+        let Some(count) = count else {
+            continue;
+        };
+
+        write!(
+            f,
+            " '{}\t{}\n'",
+            count,
+            kakplugin::escape(selections[idx].trim_end_matches('\n'))
+        )?;
+
+        for (member, _) in groups
             .iter()
-            .zip(new_selections.iter())
-            // If the string was emptied (None), then do not set `sd`
-            .filter_map(|(sd, s)| if s.is_some() { Some(sd) } else { None }),
+            .enumerate()
+            .filter(|(member, group)| **group == groups[idx] && *member != idx)
+        {
+            write!(
+                f,
+                " '\t{}\n'",
+                kakplugin::escape(selections[member].trim_end_matches('\n'))
+            )?;
+        }
+    }
+
+    write!(
+        f,
+        r";
+            edit -scratch '{KAK_BUFFER_NAME}';
+            execute-keys '%<a-R>';
+        }}"
     )?;
 
-    let old_count = new_selections.len();
-    let new_count = new_selections.iter().flatten().count();
+    f.flush()?;
 
-    Ok(format!(
-        "{} unique selections out of {}",
-        new_count, old_count
-    ))
+    Ok(())
+}
+
+/// Decides which selections to keep based on the group of each selection
+///
+/// Returns the size of the group of every kept selection, or `None` if the selection should be removed
+fn kept_counts(groups: &[usize], options: &Options) -> Vec<Option<usize>> {
+    let mut counts = BTreeMap::new();
+    // The index of the selection that represents its group
+    let mut representatives = BTreeMap::new();
+
+    for (idx, group) in groups.iter().enumerate() {
+        *counts.entry(group).or_insert(0_usize) += 1;
+
+        if options.last {
+            representatives.insert(group, idx);
+        } else {
+            representatives.entry(group).or_insert(idx);
+        }
+    }
+
+    groups
+        .iter()
+        .enumerate()
+        .map(|(idx, group)| {
+            let count = counts[group];
+
+            (representatives[group] == idx
+                && !(options.repeated && count == 1)
+                && !(options.unique && count > 1))
+                .then_some(count)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[clap(flatten)]
+        options: Options,
+    }
+
+    fn kept(groups: &[usize], args: &[&str]) -> Vec<Option<usize>> {
+        let cli = Cli::parse_from(std::iter::once("uniq").chain(args.iter().copied()));
+        kept_counts(groups, &cli.options)
+    }
+
+    #[test]
+    fn test_exact_groups() {
+        assert_eq!(exact_groups([5, 3, 5, 5, 1, 3]), vec![0, 1, 0, 0, 4, 1]);
+        assert!(exact_groups(Vec::<u64>::new()).is_empty());
+    }
+
+    #[test]
+    fn test_similar_groups() {
+        let keys = [
+            "Connection lost at 12:00:01",
+            "Connection lost at 12:00:05",
+            "Disk full",
+            "Disk ful",
+            "Connection lost at 13:10:00",
+        ];
+        assert_eq!(
+            similar_groups(&keys, Similarity::Distance(1)),
+            vec![0, 0, 2, 2, 4]
+        );
+        assert_eq!(
+            similar_groups(&keys, Similarity::Distance(4)),
+            vec![0, 0, 2, 2, 0]
+        );
+        assert_eq!(
+            similar_groups(&keys, Similarity::Ratio(0.95)),
+            vec![0, 0, 2, 2, 0]
+        );
+        assert_eq!(
+            similar_groups(&keys, Similarity::Distance(0)),
+            exact_groups(keys.iter())
+        );
+    }
+
+    #[test]
+    fn test_adjacent_groups() {
+        let keys = ["a", "a", "b", "a", "a", "a", "ab"];
+        assert_eq!(adjacent_groups(&keys, None), vec![0, 0, 2, 3, 3, 3, 6]);
+        assert_eq!(
+            adjacent_groups(&keys, Some(Similarity::Distance(1))),
+            vec![0, 0, 0, 0, 0, 0, 0]
+        );
+        assert!(adjacent_groups::<&str>(&[], None).is_empty());
+
+        // Run lengths are the group counts
+        assert_eq!(
+            kept(&adjacent_groups(&keys, None), &["-c"]),
+            vec![Some(2), None, Some(1), Some(3), None, None, Some(1)]
+        );
+    }
+
+    #[test]
+    fn test_loosen() {
+        assert_eq!(loosen("  Hello,   world!\n"), "Hello world");
+        assert_eq!(loosen("« Bonjour » — monde…"), "Bonjour monde");
+        assert_eq!(loosen("..."), "");
+    }
+
+    #[test]
+    fn test_kept_counts() {
+        // a b a a c b
+        let groups = [0, 1, 0, 0, 4, 1];
+        assert_eq!(
+            kept(&groups, &[]),
+            vec![Some(3), Some(2), None, None, Some(1), None]
+        );
+        assert_eq!(
+            kept(&groups, &["--last"]),
+            vec![None, None, None, Some(3), Some(1), Some(2)]
+        );
+        assert_eq!(
+            kept(&groups, &["-d"]),
+            vec![Some(3), Some(2), None, None, None, None]
+        );
+        assert_eq!(
+            kept(&groups, &["-u"]),
+            vec![None, None, None, None, Some(1), None]
+        );
+        assert_eq!(
+            kept(&groups, &["-dl"]),
+            vec![None, None, None, Some(3), None, Some(2)]
+        );
+    }
 }
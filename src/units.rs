@@ -0,0 +1,483 @@
+//! Offline unit table and arithmetic on quantities, like `3 MiB + 512 KiB` or `72 F in C`
+//!
+//! Quantities can be added, subtracted, multiplied and divided by plain numbers, and divided by each other.
+//! The result is in the unit of the first quantity, unless it ends with `in <UNIT>` or `to <UNIT>`
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dimension {
+    Data,
+    Time,
+    Length,
+    Mass,
+    Temperature,
+}
+
+/// A unit, converted to the base unit of its dimension by `(value + offset) * factor`
+#[derive(Debug, PartialEq)]
+pub struct Unit {
+    /// Name the result is written with
+    pub symbol: &'static str,
+    /// Other names, matched case-insensitively if they are longer than 3 characters
+    aliases: &'static [&'static str],
+    dimension: Dimension,
+    factor: f64,
+    /// Only used for temperatures
+    offset: f64,
+}
+
+impl Unit {
+    const fn new(
+        symbol: &'static str,
+        aliases: &'static [&'static str],
+        dimension: Dimension,
+        factor: f64,
+    ) -> Self {
+        Self {
+            symbol,
+            aliases,
+            dimension,
+            factor,
+            offset: 0.0,
+        }
+    }
+
+    fn to_base(&self, value: f64) -> f64 {
+        (value + self.offset) * self.factor
+    }
+
+    fn value_from_base(&self, value: f64) -> f64 {
+        value / self.factor - self.offset
+    }
+
+    /// Converts `value` in this unit to `unit`, returning an error if they measure different things
+    fn convert(&self, value: f64, unit: &Self) -> Result<f64, String> {
+        if self.dimension == unit.dimension {
+            Ok(round_noise(unit.value_from_base(self.to_base(value))))
+        } else {
+            Err(format!(
+                "Cannot convert {} ({:?}) to {} ({:?})",
+                self.symbol, self.dimension, unit.symbol, unit.dimension
+            ))
+        }
+    }
+}
+
+/// Rounds away binary floating point noise from a conversion, so `212 F` is `100 C` instead of `100.00000000000006 C`
+fn round_noise(f: f64) -> f64 {
+    if f == 0.0 || !f.is_finite() {
+        return f;
+    }
+
+    // Keep 12 significant digits
+    let scale = 10_f64.powi(11 - f.abs().log10().floor() as i32);
+    if scale.is_finite() {
+        (f * scale).round() / scale
+    } else {
+        f
+    }
+}
+
+const KIB: f64 = 1024.0;
+
+static UNITS: &[Unit] = &[
+    // Data, in bytes
+    Unit::new("bit", &["bits"], Dimension::Data, 1.0 / 8.0),
+    Unit::new("kbit", &["Kbit", "kbits"], Dimension::Data, 1e3 / 8.0),
+    Unit::new("Mbit", &["Mbits"], Dimension::Data, 1e6 / 8.0),
+    Unit::new("Gbit", &["Gbits"], Dimension::Data, 1e9 / 8.0),
+    Unit::new("B", &["byte", "bytes"], Dimension::Data, 1.0),
+    Unit::new("kB", &["KB"], Dimension::Data, 1e3),
+    Unit::new("MB", &[], Dimension::Data, 1e6),
+    Unit::new("GB", &[], Dimension::Data, 1e9),
+    Unit::new("TB", &[], Dimension::Data, 1e12),
+    Unit::new("PB", &[], Dimension::Data, 1e15),
+    Unit::new("KiB", &["Ki"], Dimension::Data, KIB),
+    Unit::new("MiB", &["M", "Mi"], Dimension::Data, KIB * KIB),
+    Unit::new("GiB", &["G", "Gi"], Dimension::Data, KIB * KIB * KIB),
+    Unit::new("TiB", &["T", "Ti"], Dimension::Data, KIB * KIB * KIB * KIB),
+    Unit::new(
+        "PiB",
+        &["P", "Pi"],
+        Dimension::Data,
+        KIB * KIB * KIB * KIB * KIB,
+    ),
+    // Time, in seconds
+    Unit::new("ns", &["nanosecond", "nanoseconds"], Dimension::Time, 1e-9),
+    Unit::new(
+        "us",
+        &["µs", "microsecond", "microseconds"],
+        Dimension::Time,
+        1e-6,
+    ),
+    Unit::new(
+        "ms",
+        &["millisecond", "milliseconds"],
+        Dimension::Time,
+        1e-3,
+    ),
+    Unit::new(
+        "s",
+        &["sec", "secs", "second", "seconds"],
+        Dimension::Time,
+        1.0,
+    ),
+    Unit::new("min", &["mins", "minute", "minutes"], Dimension::Time, 60.0),
+    Unit::new(
+        "h",
+        &["hr", "hrs", "hour", "hours"],
+        Dimension::Time,
+        3600.0,
+    ),
+    Unit::new("d", &["day", "days"], Dimension::Time, 86400.0),
+    Unit::new("w", &["wk", "week", "weeks"], Dimension::Time, 604_800.0),
+    Unit::new("y", &["yr", "year", "years"], Dimension::Time, 31_536_000.0),
+    // Length, in meters. `in` is not an inch, since it converts units
+    Unit::new(
+        "mm",
+        &["millimeter", "millimeters"],
+        Dimension::Length,
+        1e-3,
+    ),
+    Unit::new(
+        "cm",
+        &["centimeter", "centimeters"],
+        Dimension::Length,
+        1e-2,
+    ),
+    Unit::new("m", &["meter", "meters"], Dimension::Length, 1.0),
+    Unit::new("km", &["kilometer", "kilometers"], Dimension::Length, 1e3),
+    Unit::new("inch", &["inches"], Dimension::Length, 0.0254),
+    Unit::new("ft", &["foot", "feet"], Dimension::Length, 0.3048),
+    Unit::new("yd", &["yard", "yards"], Dimension::Length, 0.9144),
+    Unit::new("mi", &["mile", "miles"], Dimension::Length, 1609.344),
+    // Mass, in kilograms
+    Unit::new("mg", &["milligram", "milligrams"], Dimension::Mass, 1e-6),
+    Unit::new("g", &["gram", "grams"], Dimension::Mass, 1e-3),
+    Unit::new("kg", &["kilogram", "kilograms"], Dimension::Mass, 1.0),
+    Unit::new("t", &["tonne", "tonnes"], Dimension::Mass, 1e3),
+    Unit::new(
+        "oz",
+        &["ounce", "ounces"],
+        Dimension::Mass,
+        0.028_349_523_125,
+    ),
+    Unit::new(
+        "lb",
+        &["lbs", "pound", "pounds"],
+        Dimension::Mass,
+        0.453_592_37,
+    ),
+    // Temperature, in kelvin
+    Unit::new("K", &["kelvin"], Dimension::Temperature, 1.0),
+    Unit {
+        symbol: "C",
+        aliases: &["°C", "degC", "celsius"],
+        dimension: Dimension::Temperature,
+        factor: 1.0,
+        offset: 273.15,
+    },
+    Unit {
+        symbol: "F",
+        aliases: &["°F", "degF", "fahrenheit"],
+        dimension: Dimension::Temperature,
+        factor: 5.0 / 9.0,
+        offset: 459.67,
+    },
+];
+
+/// Finds a unit by its symbol or one of its aliases
+///
+/// # Errors
+///
+/// Will return `Err` if there is no such unit
+pub fn find_unit(name: &str) -> Result<&'static Unit, String> {
+    UNITS
+        .iter()
+        .find(|u| u.symbol == name || u.aliases.contains(&name))
+        .or_else(|| {
+            // Long names like `Hours` can be written in any case, but `mB` and `MB` are different
+            (name.chars().count() > 3).then(|| {
+                UNITS.iter().find(|u| {
+                    u.aliases
+                        .iter()
+                        .any(|a| a.chars().count() > 3 && a.eq_ignore_ascii_case(name))
+                })
+            })?
+        })
+        .ok_or_else(|| format!("Unknown unit '{name}'"))
+}
+
+/// Result of an expression with units
+///
+/// `unit` is only `None` if units cancelled out, like in `1 GiB / 1 MiB`
+#[derive(Debug, PartialEq)]
+pub struct Quantity {
+    pub value: f64,
+    pub unit: Option<&'static Unit>,
+}
+
+impl fmt::Display for Quantity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.unit {
+            Some(unit) => write!(f, "{} {}", self.value, unit.symbol),
+            None => write!(f, "{}", self.value),
+        }
+    }
+}
+
+/// Evaluates an expression with units, like `3 MiB + 512 KiB` or `90 min in h`, converting the result to `to` if there is no `in <UNIT>`
+///
+/// Returns `None` if `s` is not an expression with units, so it can be evaluated as a plain math expression instead
+pub fn eval(s: &str, to: Option<&'static Unit>) -> Option<Result<Quantity, String>> {
+    let tokens = tokenize(s)?;
+
+    // `... in <UNIT>` or `... to <UNIT>`
+    let (tokens, to) = match tokens.as_slice() {
+        [expr @ .., Token::Word(keyword), Token::Word(unit)]
+            if keyword == "in" || keyword == "to" =>
+        {
+            (expr, Some(find_unit(unit).ok()?))
+        }
+        _ => (tokens.as_slice(), to),
+    };
+
+    // Plain math like `1 + 2` is left to evalexpr
+    if !tokens
+        .iter()
+        .any(|t| matches!(t, Token::Word(w) if find_unit(w).is_ok()))
+    {
+        return None;
+    }
+
+    let mut parser = Parser { tokens, pos: 0 };
+    let value = match parser.expr() {
+        Ok(v) if parser.pos == tokens.len() => v,
+        Ok(_) | Err(Error::Syntax) => return None,
+        Err(Error::Unit(e)) => return Some(Err(e)),
+    };
+
+    Some(match (value.unit, to) {
+        (Some(unit), Some(to)) => unit.convert(value.n, to).map(|n| Quantity {
+            value: n,
+            unit: Some(to),
+        }),
+        (None, Some(to)) => Err(format!(
+            "Result has no unit, so it cannot be converted to {}",
+            to.symbol
+        )),
+        (unit, None) => Ok(Quantity {
+            value: value.n,
+            unit,
+        }),
+    })
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Number(f64),
+    Word(String),
+    Op(char),
+}
+
+/// Splits `s` into tokens, returning `None` if it has characters that cannot be in an expression with units
+fn tokenize(s: &str) -> Option<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = s.char_indices().peekable();
+    let is_word_char = |c: char| c.is_alphabetic() || c == '°';
+
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() || c == '.' {
+            let mut end = start;
+            while let Some(&(i, c)) = chars.peek() {
+                if !(c.is_ascii_digit() || c == '.' || c == '_') {
+                    break;
+                }
+                end = i + c.len_utf8();
+                chars.next();
+            }
+            tokens.push(Token::Number(s[start..end].replace('_', "").parse().ok()?));
+        } else if is_word_char(c) {
+            let mut end = start;
+            while let Some(&(i, c)) = chars.peek() {
+                if !is_word_char(c) {
+                    break;
+                }
+                end = i + c.len_utf8();
+                chars.next();
+            }
+            tokens.push(Token::Word(s[start..end].to_string()));
+        } else if "+-*/()".contains(c) {
+            tokens.push(Token::Op(c));
+            chars.next();
+        } else {
+            return None;
+        }
+    }
+
+    Some(tokens)
+}
+
+/// A number with an optional unit, before it is converted to the target unit
+#[derive(Clone, Copy, Debug)]
+struct Value {
+    n: f64,
+    unit: Option<&'static Unit>,
+}
+
+enum Error {
+    /// `s` is not an expression with units
+    Syntax,
+    /// `s` is an expression with units, but the units do not work together
+    Unit(String),
+}
+
+/// Recursive descent parser, like the one for `--exact` but with units
+///
+/// * `expr` - `a + b`, `a - b`
+/// * `term` - `a * b`, `a / b`
+/// * `unary` - `-a`
+/// * `atom` - `3`, `3 MiB`, and parentheses
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn next_op(&mut self, ops: &str) -> Option<char> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Op(c)) if ops.contains(*c) => {
+                self.pos += 1;
+                Some(*c)
+            }
+            _ => None,
+        }
+    }
+
+    fn expr(&mut self) -> Result<Value, Error> {
+        let mut lhs = self.term()?;
+        while let Some(op) = self.next_op("+-") {
+            let rhs = self.term()?;
+
+            // The result is in the unit of the left side, so `1 h + 30 min` is `1.5 h`
+            let rhs_n = match (lhs.unit, rhs.unit) {
+                (Some(l), Some(r)) => r.convert(rhs.n, l).map_err(Error::Unit)?,
+                (None, None) => rhs.n,
+                (Some(u), None) | (None, Some(u)) => {
+                    return Err(Error::Unit(format!(
+                        "Cannot add or subtract a number without a unit and {}",
+                        u.symbol
+                    )))
+                }
+            };
+            lhs.n = if op == '+' {
+                lhs.n + rhs_n
+            } else {
+                lhs.n - rhs_n
+            };
+        }
+        Ok(lhs)
+    }
+
+    fn term(&mut self) -> Result<Value, Error> {
+        let mut lhs = self.unary()?;
+        while let Some(op) = self.next_op("*/") {
+            let rhs = self.unary()?;
+
+            lhs = match (op, lhs.unit, rhs.unit) {
+                ('*', Some(_), Some(_)) => {
+                    return Err(Error::Unit(String::from(
+                        "Cannot multiply two quantities with units",
+                    )))
+                }
+                ('*', unit, None) | ('*', None, unit) => Value {
+                    n: lhs.n * rhs.n,
+                    unit,
+                },
+                // `1 GiB / 1 MiB` is a plain number
+                (_, Some(l), Some(r)) => Value {
+                    n: lhs.n / r.convert(rhs.n, l).map_err(Error::Unit)?,
+                    unit: None,
+                },
+                (_, unit, None) => Value {
+                    n: lhs.n / rhs.n,
+                    unit,
+                },
+                (_, None, Some(r)) => {
+                    return Err(Error::Unit(format!("Cannot divide by {}", r.symbol)))
+                }
+            };
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Value, Error> {
+        if self.next_op("-").is_some() {
+            let v = self.unary()?;
+            Ok(Value { n: -v.n, ..v })
+        } else {
+            self.atom()
+        }
+    }
+
+    fn atom(&mut self) -> Result<Value, Error> {
+        if self.next_op("(").is_some() {
+            let v = self.expr()?;
+            return self.next_op(")").map(|_| v).ok_or(Error::Syntax);
+        }
+
+        let n = match self.tokens.get(self.pos) {
+            Some(Token::Number(n)) => *n,
+            _ => return Err(Error::Syntax),
+        };
+        self.pos += 1;
+
+        let unit = match self.tokens.get(self.pos) {
+            Some(Token::Word(w)) => {
+                self.pos += 1;
+                // Any other word is probably a variable for evalexpr
+                Some(find_unit(w).map_err(|_| Error::Syntax)?)
+            }
+            _ => None,
+        };
+
+        Ok(Value { n, unit })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval_str(s: &str, to: Option<&str>) -> Option<Result<String, String>> {
+        eval(s, to.map(|u| find_unit(u).unwrap())).map(|r| r.map(|q| q.to_string()))
+    }
+
+    #[test]
+    fn test_eval() {
+        let ok = |s: &str| Some(Ok(String::from(s)));
+
+        assert_eq!(eval_str("3 MiB + 512 KiB", None), ok("3.5 MiB"));
+        assert_eq!(eval_str("90 min in h", None), ok("1.5 h"));
+        assert_eq!(eval_str("212 F in C", None), ok("100 C"));
+        assert_eq!(eval_str("-40 °C to F", None), ok("-40 F"));
+        assert_eq!(eval_str("1536 KiB", Some("MiB")), ok("1.5 MiB"));
+        assert_eq!(eval_str("1_536KiB", None), ok("1536 KiB"));
+        assert_eq!(eval_str("2 * (1 h - 15 min)", None), ok("1.5 h"));
+        assert_eq!(eval_str("1 GiB / 4", None), ok("0.25 GiB"));
+        assert_eq!(eval_str("1 GiB / 256 MiB", None), ok("4"));
+        assert_eq!(eval_str("3 Hours in MINUTES", None), ok("180 min"));
+        assert_eq!(eval_str("1 + 2", None), None);
+        assert_eq!(eval_str("x * 2", None), None);
+        assert_eq!(eval_str("2 * pi", None), None);
+        assert_eq!(eval_str("s[1] + 2", None), None);
+
+        assert!(matches!(eval_str("3 MiB + 2 h", None), Some(Err(_))));
+        assert!(matches!(eval_str("3 MiB in kg", None), Some(Err(_))));
+        assert!(matches!(eval_str("3 MiB + 2", None), Some(Err(_))));
+        assert!(matches!(eval_str("3 m * 2 m", None), Some(Err(_))));
+        assert!(matches!(eval_str("1 h / 1 min", Some("s")), Some(Err(_))));
+    }
+}
@@ -1,60 +1,204 @@
 // use kakplugin::Selection;
+use alphanumeric_sort::compare_str;
+use kakplugin::{types::SelectionDesc, KakError};
 use regex::Regex;
 use std::{
     borrow::Cow,
+    cmp::Ordering,
     collections::hash_map::DefaultHasher,
     hash::{Hash, Hasher},
 };
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+/// A unicode normalization form
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Normalization {
+    Nfc,
+    Nfd,
+    Nfkc,
+    Nfkd,
+}
+
+impl Normalization {
+    /// Normalizes a string to this form, only allocating if it is not already normalized
+    pub fn normalize(self, s: Cow<'_, str>) -> Cow<'_, str> {
+        match self {
+            Self::Nfc if !unicode_normalization::is_nfc(&s) => Cow::Owned(s.nfc().collect()),
+            Self::Nfd if !unicode_normalization::is_nfd(&s) => Cow::Owned(s.nfd().collect()),
+            Self::Nfkc if !unicode_normalization::is_nfkc(&s) => Cow::Owned(s.nfkc().collect()),
+            Self::Nfkd if !unicode_normalization::is_nfkd(&s) => Cow::Owned(s.nfkd().collect()),
+            _ => s,
+        }
+    }
+}
+
+/// Unicode handling for keys returned by `get_key`
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct UnicodeOptions {
+    #[clap(long, value_enum, help = "Unicode normalization form to apply to keys")]
+    pub normalize: Option<Normalization>,
+    #[clap(short = 'a', long, help = "Ignore accents and other diacritics")]
+    pub ignore_accents: bool,
+}
+
+/// Field extraction for keys returned by `get_key`
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct FieldOptions {
+    #[clap(
+        short = 'k',
+        long,
+        value_parser = clap::value_parser!(u16).range(1..),
+        help = "Use only this field (starting at 1) as the key"
+    )]
+    pub field: Option<u16>,
+    #[clap(
+        short = 't',
+        long,
+        requires = "field",
+        help = "Field separator (default: runs of whitespace, or ',' with --csv)"
+    )]
+    pub field_separator: Option<String>,
+    #[clap(
+        long,
+        requires = "field",
+        help = "Allow fields to be double quoted, like in a CSV file"
+    )]
+    pub csv: bool,
+}
+
+impl FieldOptions {
+    /// Gets the requested field out of `s`, or all of `s` if no field was requested
+    pub fn extract<'a>(&self, s: &'a str) -> Cow<'a, str> {
+        self.field.map_or(Cow::Borrowed(s), |field| {
+            get_field(
+                s,
+                usize::from(field.saturating_sub(1)),
+                self.field_separator
+                    .as_deref()
+                    .or_else(|| self.csv.then_some(",")),
+                self.csv,
+            )
+        })
+    }
+}
+
+/// Options for turning a selection into a key with `get_key`, shared by every command that compares selections
+#[derive(clap::Args, Clone, Debug, Default)]
+pub struct KeyOptions {
+    #[clap(
+        short = 'R',
+        long,
+        help = "Compare only the regex match (or its first capture group)"
+    )]
+    pub regex: Option<Regex>,
+    #[clap(
+        short = 'S',
+        long,
+        help = "Do not trim surrounding whitespace before comparing"
+    )]
+    pub no_skip_whitespace: bool,
+    #[clap(short, long, help = "Ignore case when comparing")]
+    pub ignore_case: bool,
+    #[clap(
+        short = 'q',
+        long,
+        help = "Remove a pair of surrounding quotes before comparing"
+    )]
+    pub strip_quotes: bool,
+    #[clap(
+        short,
+        long,
+        help = "Compare numbers by value, so '1', '01', and '1.0' are the same key"
+    )]
+    pub numeric: bool,
+    #[clap(flatten)]
+    pub fields: FieldOptions,
+    #[clap(flatten)]
+    pub unicode: UnicodeOptions,
+}
+
+impl KeyOptions {
+    /// Overrides the regex, for commands that also accept the regex as a positional argument
+    pub fn with_regex(&self, regex: Option<&Regex>) -> Cow<'_, Self> {
+        regex.map_or(Cow::Borrowed(self), |regex| {
+            Cow::Owned(Self {
+                regex: Some(regex.clone()),
+                ..self.clone()
+            })
+        })
+    }
+}
 
 /// Gets a key out of a selection
 ///
+/// Keys are transformed in this order: whitespace is trimmed, the field is extracted, quotes are stripped,
+/// the regex is matched, numbers are normalized, unicode is normalized, and then case is folded
+///
 /// # Examples
 ///
 /// ```
-/// assert_eq!(get_key("  asdf\n", false, None, false), "asdf\n");
-/// assert_eq!(get_key("  asdf\n", true, None, false), "  asdf\n");
-/// assert_eq!(get_key("  as1f\n", false, Some("\w+"), false), "as");
-/// assert_eq!(get_key("  aS1F\n", false, Some("\w+"), true), "as1f");
+/// let o = KeyOptions::default();
+/// assert_eq!(get_key("  asdf\n", &o), "asdf");
+/// assert_eq!(get_key("  asdf\n", &KeyOptions { no_skip_whitespace: true, ..o }), "  asdf\n");
+/// assert_eq!(get_key("  as1f\n", &KeyOptions { regex: Regex::new("\w+").ok(), ..o }), "as");
+/// assert_eq!(get_key("Ärger", &KeyOptions { ignore_case: true, ..o }), "ärger");
 /// ```
-pub fn get_key<'sel>(
-    selection: &'sel str,
-    preserve_whitespace: bool,
-    regex: Option<&Regex>,
-    ignore_case: bool,
-) -> Cow<'sel, str> {
+pub fn get_key<'sel>(selection: &'sel str, options: &KeyOptions) -> Cow<'sel, str> {
     // Strip whitespace if requested
-    let mut key = if preserve_whitespace {
-        // TODO: Does this need to be swapped?
+    let key = if options.no_skip_whitespace {
         selection
     } else {
         selection.trim()
     };
 
+    // Narrow the key down to a single field before matching the regex
+    let mut key = options.fields.extract(key);
+
+    if options.strip_quotes {
+        key = match key {
+            Cow::Borrowed(k) => Cow::Borrowed(strip_quotes(k)),
+            Cow::Owned(k) => Cow::Owned(strip_quotes(&k).to_string()),
+        };
+    }
+
     // If they requested a regex match, set the key to the string slice of that match
-    if let Some(regex_match) = (|| {
-        // let captures = regex.as_ref()?.captures(&key)?;
-        let captures = regex.as_ref()?.captures(key)?;
+    if let Some(range) = (|| {
+        let captures = options.regex.as_ref()?.captures(&key)?;
         captures
             .get(1)
             .or_else(|| captures.get(0))
-            .map(|m| m.as_str())
+            .map(|m| m.range())
     })() {
-        key = regex_match;
-        // Cow::Borrowed(regex_match)
+        key = match key {
+            Cow::Borrowed(k) => Cow::Borrowed(&k[range]),
+            Cow::Owned(k) => Cow::Owned(k[range].to_string()),
+        };
+    }
+
+    if options.numeric {
+        if let Some(number) = normalize_number(&key) {
+            key = Cow::Owned(number);
+        }
+    }
+
+    // Normalize after the regex so the regex is matched against what the user sees
+    if options.unicode.ignore_accents {
+        key = match key {
+            Cow::Borrowed(k) => strip_accents(k),
+            Cow::Owned(k) => Cow::Owned(strip_accents(&k).into_owned()),
+        };
+    }
+
+    if let Some(normalization) = options.unicode.normalize {
+        key = normalization.normalize(key);
     }
 
     // Ignore case if requested
-    if ignore_case {
+    if options.ignore_case {
         // Lowercase at the end to not mangle regex
-        // TODO: Do not allocate if it is already lowercased
-        // Need to_lowercase(&self) -> Cow<str>
-        if !key.as_bytes().iter().any(u8::is_ascii_uppercase) {
-            Cow::Borrowed(key)
-        } else {
-            Cow::Owned(key.to_ascii_lowercase())
-        }
+        fold_case(key)
     } else {
-        Cow::Borrowed(key)
+        key
     }
 }
 
@@ -62,17 +206,251 @@ pub fn get_key<'sel>(
 pub fn get_hash(
     // TODO: Accept any Into<AsRef<Selection>>
     selection: &str,
-    preserve_whitespace: bool,
-    regex: Option<&Regex>,
-    ignore_case: bool,
+    options: &KeyOptions,
 ) -> u64 {
     let mut hasher = DefaultHasher::new();
 
-    get_key(&selection, preserve_whitespace, regex, ignore_case).hash(&mut hasher);
+    get_key(selection, options).hash(&mut hasher);
 
     hasher.finish()
 }
 
+/// Removes one pair of matching quotes (`"`, `'`, or `` ` ``) surrounding `s`
+///
+/// # Examples
+///
+/// ```
+/// assert_eq!(strip_quotes("\"asdf\""), "asdf");
+/// assert_eq!(strip_quotes("'asdf\""), "'asdf\"");
+/// ```
+pub fn strip_quotes(s: &str) -> &str {
+    ['"', '\'', '`']
+        .iter()
+        .find_map(|q| s.strip_prefix(*q)?.strip_suffix(*q))
+        .unwrap_or(s)
+}
+
+/// Gets a canonical representation of a number, so numbers that are equal have the same key
+///
+/// The key is built from the digits instead of a float, so long integers like IDs stay distinct.
+/// Returns `None` if `s` is not a number
+///
+/// # Examples
+///
+/// ```
+/// assert_eq!(normalize_number("+01.50").as_deref(), Some("1.5"));
+/// assert_eq!(normalize_number("1_000").as_deref(), Some("1000"));
+/// assert_eq!(normalize_number("2.5e2").as_deref(), Some("250"));
+/// assert_eq!(normalize_number("-0"), Some("0"));
+/// assert_eq!(normalize_number("abc"), None);
+/// ```
+pub fn normalize_number(s: &str) -> Option<String> {
+    let s = s.replace('_', "");
+
+    // Only accept what a float accepts, but without infinity or NaN
+    if !s.parse::<f64>().ok()?.is_finite() {
+        return None;
+    }
+
+    let negative = s.starts_with('-');
+    let unsigned = s.trim_start_matches(['-', '+']);
+    let (mantissa, exponent) = match unsigned.split_once(['e', 'E']) {
+        Some((mantissa, exponent)) => (mantissa, exponent.parse::<i64>().ok()?),
+        None => (unsigned, 0),
+    };
+    let (whole, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+
+    // The number is `digits * 10^exponent`, with no leading or trailing zeros in `digits`
+    let digits = format!("{whole}{fraction}");
+    let digits = digits.trim_start_matches('0');
+    let trimmed = digits.trim_end_matches('0');
+    let exponent = exponent
+        .checked_sub(i64::try_from(fraction.len()).ok()?)?
+        .checked_add(i64::try_from(digits.len() - trimmed.len()).ok()?)?;
+
+    if trimmed.is_empty() {
+        return Some(String::from("0"));
+    }
+
+    // Position of the decimal point in `trimmed`
+    let point = i64::try_from(trimmed.len()).ok()?.checked_add(exponent)?;
+    let sign = if negative { "-" } else { "" };
+
+    Some(if exponent >= 0 {
+        // Finite floats are less than 10^309, so this is never too long
+        format!(
+            "{sign}{trimmed}{}",
+            "0".repeat(usize::try_from(exponent).ok()?)
+        )
+    } else if point > 0 {
+        let (whole, fraction) = trimmed.split_at(usize::try_from(point).ok()?);
+        format!("{sign}{whole}.{fraction}")
+    } else if point > -400 {
+        format!(
+            "{sign}0.{}{trimmed}",
+            "0".repeat(usize::try_from(-point).ok()?)
+        )
+    } else {
+        // Too small to write out, but still distinct from other numbers
+        format!("{sign}{trimmed}e{exponent}")
+    })
+}
+
+/// Gets the `n`th (starting at 0) field out of `s`
+///
+/// Fields are split on `separator`, or on runs of whitespace if it is `None`.
+/// If `quoted`, a field can be surrounded with double quotes (`""` being an escaped quote) to contain the separator.
+/// Missing fields are returned as an empty string
+///
+/// # Examples
+///
+/// ```
+/// assert_eq!(get_field("a  b c", 1, None, false), "b");
+/// assert_eq!(get_field("a,b,c", 2, Some(","), false), "c");
+/// assert_eq!(get_field(r#"a,"b,""c""",d"#, 1, Some(","), true), r#"b,"c""#);
+/// assert_eq!(get_field("a,b", 5, Some(","), false), "");
+/// ```
+pub fn get_field<'a>(s: &'a str, n: usize, separator: Option<&str>, quoted: bool) -> Cow<'a, str> {
+    let mut rest = Some(if separator.is_none() { s.trim() } else { s });
+
+    for _ in 0..n {
+        rest = rest.and_then(|r| next_field(r, separator, quoted).1);
+    }
+
+    rest.map_or(Cow::Borrowed(""), |r| next_field(r, separator, quoted).0)
+}
+
+/// Splits the first field off of `s`
+///
+/// Returns the field and everything after its separator, or `None` if this was the last field
+fn next_field<'a>(
+    s: &'a str,
+    separator: Option<&str>,
+    quoted: bool,
+) -> (Cow<'a, str>, Option<&'a str>) {
+    // A quoted field ends at its closing quote instead of the first separator
+    let (field, rest) = s
+        .strip_prefix('"')
+        .filter(|_| quoted)
+        .and_then(split_quoted)
+        .unwrap_or_else(|| {
+            let end = find_separator(s, separator).map_or(s.len(), |(start, _)| start);
+            (Cow::Borrowed(&s[..end]), &s[end..])
+        });
+
+    (
+        field,
+        find_separator(rest, separator).map(|(_, end)| &rest[end..]),
+    )
+}
+
+/// Finds the start and end of the first separator in `s`
+fn find_separator(s: &str, separator: Option<&str>) -> Option<(usize, usize)> {
+    separator.map_or_else(
+        || {
+            let start = s.find(char::is_whitespace)?;
+            let end = s[start..]
+                .find(|c: char| !c.is_whitespace())
+                .map_or(s.len(), |len| start + len);
+            Some((start, end))
+        },
+        |sep| s.find(sep).map(|start| (start, start + sep.len())),
+    )
+}
+
+/// Splits a quoted field (without its opening quote) at its closing quote
+///
+/// Returns `None` if the quote is never closed
+fn split_quoted(s: &str) -> Option<(Cow<'_, str>, &str)> {
+    let mut search_from = 0;
+    let mut has_escapes = false;
+
+    loop {
+        let quote = search_from + s[search_from..].find('"')?;
+
+        if s[quote + 1..].starts_with('"') {
+            // `""` is an escaped quote, not the end of the field
+            has_escapes = true;
+            search_from = quote + 2;
+            continue;
+        }
+
+        let field = &s[..quote];
+        return Some((
+            if has_escapes {
+                Cow::Owned(field.replace("\"\"", "\""))
+            } else {
+                Cow::Borrowed(field)
+            },
+            &s[quote + 1..],
+        ));
+    }
+}
+
+/// Unicode case folds a string, only allocating if there is something to fold
+///
+/// Unlike `to_lowercase`, this maps characters like `ß` to `ss` so caseless comparisons are correct
+pub fn fold_case(s: Cow<'_, str>) -> Cow<'_, str> {
+    if s.is_ascii() {
+        if s.as_bytes().iter().any(u8::is_ascii_uppercase) {
+            Cow::Owned(s.to_ascii_lowercase())
+        } else {
+            s
+        }
+    } else {
+        let folded = caseless::default_case_fold_str(&s);
+        if folded == s {
+            s
+        } else {
+            Cow::Owned(folded)
+        }
+    }
+}
+
+/// Removes accents and other combining marks from a string by decomposing it (NFKD)
+///
+/// # Examples
+///
+/// ```
+/// assert_eq!(strip_accents("Ärger"), "Arger");
+/// assert_eq!(strip_accents("ﬁancé"), "fiance");
+/// ```
+pub fn strip_accents(s: &str) -> Cow<'_, str> {
+    if s.is_ascii() {
+        Cow::Borrowed(s)
+    } else {
+        Cow::Owned(s.nfkd().filter(|c| !is_combining_mark(*c)).collect())
+    }
+}
+
+/// Compares two keys using a simplified multi-level unicode collation
+///
+/// Keys are first compared by their base letters (accents removed, case folded), then by their accents, then by case.
+/// This keeps `arger`, `Arger`, and `Ärger` next to each other instead of sorting `Ä` after `z`
+///
+/// * `natural` - Compare numbers in keys by value (`2 < 10`) at every level
+pub fn collate(a: &str, b: &str, natural: bool) -> Ordering {
+    let compare = |a: &str, b: &str| {
+        if natural {
+            compare_str(a, b)
+        } else {
+            a.cmp(b)
+        }
+    };
+
+    // Primary: base letters only
+    compare(&fold_case(strip_accents(a)), &fold_case(strip_accents(b)))
+        // Secondary: accents, but not case
+        .then_with(|| {
+            compare(
+                &fold_case(Cow::Owned(a.nfd().collect())),
+                &fold_case(Cow::Owned(b.nfd().collect())),
+            )
+        })
+        // Tertiary: everything else
+        .then_with(|| compare(a, b))
+}
+
 /// Splits an `&str` into (`leading_newlines`, `string_value`, `trailing_newlines`)
 ///
 /// # Examples
@@ -94,3 +472,281 @@ pub fn split_newlines(s: &'_ str) -> (&'_ str, &'_ str, &'_ str) {
 
     (leading_newlines, s, trailing_newlines)
 }
+
+/// Inserts `separator` between every group of three digits, counting from the right
+pub fn group_thousands(digits: &str, separator: char) -> String {
+    let mut ret = String::with_capacity(digits.len() + digits.len() / 3);
+    // Number of digits before the first separator, if it is not a full group
+    let offset = digits.len() % 3;
+
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && i % 3 == offset {
+            ret.push(separator);
+        }
+        ret.push(c);
+    }
+
+    ret
+}
+
+/// What to do with a selection that could not be evaluated
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Keep the original content
+    #[default]
+    Keep,
+    /// Replace the selection with an empty string
+    Empty,
+    /// Replace the selection with the error marker
+    Marker,
+    /// Do not change any selections
+    Abort,
+}
+
+/// Error handling for commands that evaluate each selection
+#[derive(clap::Args, Clone, Debug)]
+pub struct ErrorOptions {
+    #[clap(
+        long,
+        value_enum,
+        default_value_t,
+        help = "What to do with selections that could not be evaluated"
+    )]
+    pub on_error: ErrorPolicy,
+    #[clap(
+        long,
+        default_value = "#ERR",
+        help = "Text to replace failed selections with when using --on-error=marker"
+    )]
+    pub error_marker: String,
+}
+
+/// A selection that could not be evaluated
+#[derive(Debug)]
+pub struct EvalError<'a> {
+    pub desc: SelectionDesc,
+    pub content: &'a str,
+    pub error: String,
+}
+
+impl ErrorOptions {
+    /// Gets the text a failed selection should be replaced with
+    pub fn replacement<'a>(&'a self, original: &'a str) -> &'a str {
+        match self.on_error {
+            ErrorPolicy::Keep | ErrorPolicy::Abort => original,
+            ErrorPolicy::Empty => "",
+            ErrorPolicy::Marker => &self.error_marker,
+        }
+    }
+
+    /// Lists every failed selection and its position, to be shown as the details of the command's message
+    ///
+    /// Returns `None` if there are no errors
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` with the list as details if there are any errors and the policy is `Abort`, so the caller can stop before writing anything
+    pub fn report(&self, errors: &[EvalError<'_>]) -> Result<Option<String>, KakError> {
+        if errors.is_empty() {
+            return Ok(None);
+        }
+
+        let details = format!(
+            "{} selection{} could not be evaluated:\n{}",
+            errors.len(),
+            if errors.len() == 1 { "" } else { "s" },
+            errors
+                .iter()
+                .map(|e| format!("{}: '{}': {}", e.desc, e.content, e.error))
+                .collect::<Vec<_>>()
+                .join("\n")
+        );
+
+        if self.on_error == ErrorPolicy::Abort {
+            return Err(KakError::CustomDetails(
+                format!(
+                    "Aborted because {} selection{} could not be evaluated",
+                    errors.len(),
+                    if errors.len() == 1 { "" } else { "s" }
+                ),
+                details,
+            ));
+        }
+
+        Ok(Some(details))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_key() {
+        let o = KeyOptions::default();
+
+        assert_eq!(get_key("  asdf\n", &o), "asdf");
+        assert_eq!(
+            get_key(
+                "  asdf\n",
+                &KeyOptions {
+                    no_skip_whitespace: true,
+                    ..KeyOptions::default()
+                }
+            ),
+            "  asdf\n"
+        );
+        assert_eq!(
+            get_key(
+                " 'id: 0042' ",
+                &KeyOptions {
+                    strip_quotes: true,
+                    numeric: true,
+                    regex: Regex::new(r"\d+").ok(),
+                    ..KeyOptions::default()
+                }
+            ),
+            "42"
+        );
+        // Not a number, so it is left alone
+        assert_eq!(
+            get_key(
+                "0x10",
+                &KeyOptions {
+                    numeric: true,
+                    ..KeyOptions::default()
+                }
+            ),
+            "0x10"
+        );
+    }
+
+    #[test]
+    fn test_normalize_number() {
+        for (a, b) in [
+            ("1", "1.0"),
+            ("-1.5", "-1.50"),
+            ("007", "7"),
+            ("0.0", "-0"),
+            ("1e3", "1000"),
+            ("0.00012", "1.2e-4"),
+            (".5", "0.5"),
+            ("1_000_000", "1000000"),
+        ] {
+            assert_eq!(normalize_number(a), normalize_number(b), "{a} and {b}");
+        }
+
+        // Long IDs are more precise than a float, but are still different numbers
+        assert_eq!(
+            normalize_number("123456789012345678").as_deref(),
+            Some("123456789012345678")
+        );
+        assert_ne!(
+            normalize_number("123456789012345678"),
+            normalize_number("123456789012345679")
+        );
+        assert_ne!(
+            normalize_number("0.1000000000000000001"),
+            normalize_number("0.1")
+        );
+
+        assert_eq!(normalize_number("-0.0012").as_deref(), Some("-0.0012"));
+        assert_eq!(normalize_number("1e-500").as_deref(), Some("1e-500"));
+        assert_eq!(normalize_number("1e400"), None);
+        assert_eq!(normalize_number("inf"), None);
+        assert_eq!(normalize_number("1.2.3"), None);
+    }
+
+    #[test]
+    fn test_get_key_unicode() {
+        let case = KeyOptions {
+            ignore_case: true,
+            ..KeyOptions::default()
+        };
+        let accents = KeyOptions {
+            ignore_case: true,
+            unicode: UnicodeOptions {
+                ignore_accents: true,
+                ..UnicodeOptions::default()
+            },
+            ..KeyOptions::default()
+        };
+        let nfc = KeyOptions {
+            unicode: UnicodeOptions {
+                normalize: Some(Normalization::Nfc),
+                ..UnicodeOptions::default()
+            },
+            ..KeyOptions::default()
+        };
+
+        assert_eq!(get_key(" Ärger ", &case), "ärger");
+        assert_eq!(get_key("Straße", &case), "strasse");
+        assert_eq!(get_key("Ärger", &accents), "arger");
+        // Decomposed and precomposed forms are equal after NFC
+        assert_eq!(get_key("A\u{308}rger", &nfc), get_key("\u{c4}rger", &nfc));
+        assert!(matches!(get_key("asdf", &accents), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn test_get_field() {
+        assert_eq!(get_field("  a \t b  c ", 0, None, false), "a");
+        assert_eq!(get_field("  a \t b  c ", 2, None, false), "c");
+        assert_eq!(get_field("  a \t b  c ", 3, None, false), "");
+        assert_eq!(get_field("a,,c", 1, Some(","), false), "");
+        assert_eq!(get_field("a::b::c", 2, Some("::"), false), "c");
+
+        // Quotes are only special when requested
+        assert_eq!(get_field(r#""a,b",c"#, 0, Some(","), false), r#""a"#);
+        assert_eq!(get_field(r#""a,b",c"#, 0, Some(","), true), "a,b");
+        assert_eq!(get_field(r#""a,b",c"#, 1, Some(","), true), "c");
+        assert_eq!(
+            get_field(r#"x,"say ""hi""""#, 1, Some(","), true),
+            r#"say "hi""#
+        );
+        // Unterminated quotes are treated as regular text
+        assert_eq!(get_field(r#""a,b"#, 0, Some(","), true), r#""a"#);
+    }
+
+    #[test]
+    fn test_get_key_field() {
+        let o = KeyOptions {
+            ignore_case: true,
+            fields: FieldOptions {
+                field: Some(3),
+                field_separator: None,
+                csv: true,
+            },
+            ..KeyOptions::default()
+        };
+
+        assert_eq!(get_key(r#" 1,"Doe, Jane",Zürich "#, &o), "zürich");
+        assert_eq!(
+            get_key(
+                r#"1,"Doe, ""J""",id-42"#,
+                &KeyOptions {
+                    regex: Regex::new(r"\d+").ok(),
+                    ..o
+                }
+            ),
+            "42"
+        );
+    }
+
+    #[test]
+    fn test_collate() {
+        let mut names = vec!["zebra", "Ärger", "arger", "Arger", "apfel"];
+        names.sort_by(|a, b| collate(a, b, true));
+        assert_eq!(names, vec!["apfel", "Arger", "arger", "Ärger", "zebra"]);
+
+        assert_eq!(collate("item 2", "item 10", true), Ordering::Less);
+        assert_eq!(collate("item 2", "item 10", false), Ordering::Greater);
+    }
+
+    #[test]
+    fn test_group_thousands() {
+        assert_eq!(group_thousands("1", ','), "1");
+        assert_eq!(group_thousands("123", ','), "123");
+        assert_eq!(group_thousands("1234", ','), "1,234");
+        assert_eq!(group_thousands("1234567", '_'), "1_234_567");
+    }
+}
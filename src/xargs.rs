@@ -1,49 +1,708 @@
-use kakplugin::{get_selections_with_desc, set_selections_failable, KakError};
+use kakplugin::{
+    get_selections_with_desc, set_selections, set_selections_failable,
+    types::{AnchorPosition, Register, SelectionWithDesc},
+    KakError,
+};
 use std::{
-    io::{BufRead, BufReader, Write},
-    process::{Command, Stdio},
+    cmp::Ordering as CmpOrdering,
+    env,
+    io::{self, Read, Write},
+    iter,
+    num::NonZeroUsize,
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError},
+    },
+    thread,
+    time::{Duration, Instant},
 };
+
+/// Replaced with the content of the selection in `--each` mode
+const PLACEHOLDER: &str = "{}";
+/// Name of the shell script (`$0`) in `--shell` mode
+const SHELL_SCRIPT_NAME: &str = "xargs";
+/// How often to check whether a command with a timeout has exited
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
 #[derive(clap::Args, Debug)]
 pub struct Options {
-    #[clap()]
-    command: String,
-    #[clap(allow_hyphen_values = true)]
-    args: Vec<String>,
+    #[clap(
+        short,
+        long,
+        help = "Run the command once for each selection, with the selection on stdin or substituted for {} in the arguments"
+    )]
+    each: bool,
+    #[clap(
+        short = 'c',
+        long,
+        help = "Run the command as a script with $SHELL -c. ARGS are passed as $1, $2, ..., after the selection with --each"
+    )]
+    shell: bool,
+    #[clap(
+        short,
+        long,
+        requires = "each",
+        value_parser = clap::value_parser!(u16).range(1..),
+        help = "Number of commands to run at once with --each (default: number of CPUs)"
+    )]
+    jobs: Option<u16>,
+    #[clap(
+        short,
+        long,
+        default_value = "\\0",
+        value_parser = parse_delimiter,
+        conflicts_with = "each",
+        help = "Delimiter between selections written to the command and between its results. Escapes like \\0, \\n, and \\t are allowed"
+    )]
+    delimiter: String,
+    #[clap(
+        long,
+        value_parser = parse_delimiter,
+        conflicts_with_all = ["each", "json"],
+        help = "Delimiter between selections written to the command, instead of --delimiter"
+    )]
+    input_delimiter: Option<String>,
+    #[clap(
+        long,
+        value_parser = parse_delimiter,
+        conflicts_with = "each",
+        help = "Delimiter between the command's results, instead of --delimiter"
+    )]
+    output_delimiter: Option<String>,
+    #[clap(
+        short,
+        long,
+        conflicts_with = "each",
+        help = "Fail if the command does not write exactly one result for each selection"
+    )]
+    strict: bool,
+    #[clap(
+        long,
+        help = "Replace selections even if the command exits unsuccessfully"
+    )]
+    ignore_status: bool,
+    #[clap(
+        short,
+        long,
+        value_name = "SECONDS",
+        value_parser = parse_timeout,
+        help = "Kill the command if it runs longer than this many seconds"
+    )]
+    timeout: Option<Duration>,
+    #[clap(
+        long,
+        help = "Write each selection to the command as a line of JSON with its index, description, buffer name, and registers"
+    )]
+    json: bool,
+    #[clap(
+        short,
+        long,
+        help = "Pass the values of this register to the command in $kak_reg_<name>. Can be given multiple times"
+    )]
+    register: Vec<Register>,
+    /// The command and its arguments
+    ///
+    /// These are a single trailing argument so that flags after the command, like `sed -e`, belong to the command
+    #[clap(
+        required = true,
+        num_args = 1..,
+        allow_hyphen_values = true,
+        trailing_var_arg = true,
+        value_name = "COMMAND",
+        help = "Command to run, and its arguments"
+    )]
+    argv: Vec<String>,
 }
+
 pub fn xargs(options: &Options) -> Result<String, KakError> {
-    let mut child = Command::new(&options.command)
-        .args(&options.args)
-        .stdin(Stdio::piped())
+    let (command, args) = options
+        .argv
+        .split_first()
+        .ok_or(KakError::CustomStatic("No command given"))?;
+
+    let selections = get_selections_with_desc(None)?;
+    let metadata = Metadata::get(options, selections.len())?;
+
+    if options.each {
+        return xargs_each(options, command, args, &selections, &metadata);
+    }
+
+    let input_delimiter = options
+        .input_delimiter
+        .as_ref()
+        .unwrap_or(&options.delimiter);
+    let output_delimiter = options
+        .output_delimiter
+        .as_ref()
+        .unwrap_or(&options.delimiter);
+
+    let mut input = String::new();
+    for (i, s) in selections.iter().enumerate() {
+        if options.json {
+            input.push_str(&selection_json(i, s, &metadata));
+            input.push('\n');
+        } else {
+            input.push_str(&s.content);
+            input.push_str(input_delimiter);
+        }
+    }
+    let output = run_command(options, command, args, Some(input), &metadata.env())?;
+
+    let records = split_records(&output, output_delimiter.as_bytes());
+    if options.strict {
+        check_record_count(&records, &selections, command)?;
+    }
+
+    let num_set = set_selections_failable(
+        records
+            .into_iter()
+            // TODO: Support non-utf8?
+            .map(|r| String::from_utf8(r.to_vec())),
+    )?;
+
+    Ok(format!("Set {num_set} selections from {command}"))
+}
+
+/// Information about the buffer that every command gets
+struct Metadata {
+    bufname: String,
+    selection_count: usize,
+    /// Values of each register given with `--register`
+    registers: Vec<(Register, Vec<String>)>,
+}
+
+impl Metadata {
+    fn get(options: &Options, selection_count: usize) -> Result<Self, KakError> {
+        let bufname = kakplugin::response("%val[bufname]", None::<&str>)?
+            .into_iter()
+            .next()
+            .unwrap_or_default();
+
+        let registers = options
+            .register
+            .iter()
+            .map(|r| match kakplugin::reg(*r, None) {
+                Ok(values) => Ok((*r, values)),
+                Err(KakError::EmptyRegister(_)) => Ok((*r, Vec::new())),
+                Err(e) => Err(e),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            bufname,
+            selection_count,
+            registers,
+        })
+    }
+
+    /// Environment variables for every command, named like the ones kakoune sets in `%sh{}` blocks
+    ///
+    /// Registers are shell quoted, since they can have multiple values
+    fn env(&self) -> Vec<(String, String)> {
+        let mut env = vec![
+            (String::from("kak_bufname"), self.bufname.clone()),
+            (
+                String::from("kak_selection_count"),
+                self.selection_count.to_string(),
+            ),
+        ];
+
+        env.extend(self.registers.iter().map(|(r, values)| {
+            (
+                format!("kak_reg_{}", r.kak_expanded()),
+                shell_words::join(values),
+            )
+        }));
+
+        env
+    }
+}
+
+/// One selection as a line of JSON for `--json`
+///
+/// The index starts at 1, like the selection count shown by kakoune
+fn selection_json(i: usize, selection: &SelectionWithDesc, metadata: &Metadata) -> String {
+    let position = |p: AnchorPosition| serde_json::json!({ "row": p.row, "col": p.col });
+
+    serde_json::json!({
+        "index": i + 1,
+        "desc": selection.desc.to_string(),
+        "anchor": position(selection.desc.left),
+        "cursor": position(selection.desc.right),
+        "content": selection.content,
+        "bufname": metadata.bufname,
+        "registers": metadata
+            .registers
+            .iter()
+            .map(|(r, values)| (r.kak_expanded().to_string(), serde_json::json!(values)))
+            .collect::<serde_json::Map<_, _>>(),
+    })
+    .to_string()
+}
+
+/// Runs the command to completion and returns its stdout, writing `input` to its stdin if there is any
+///
+/// Fails if the command cannot be started, runs past `--timeout`, or exits unsuccessfully (unless `--ignore-status` is given)
+fn run_command<I, S>(
+    options: &Options,
+    command: &str,
+    args: I,
+    input: Option<String>,
+    env: &[(String, String)],
+) -> Result<Vec<u8>, KakError>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<std::ffi::OsStr>,
+{
+    let mut process = if options.shell {
+        let mut process = Command::new(env::var_os("SHELL").unwrap_or_else(|| "sh".into()));
+        process.arg("-c").arg(command).arg(SHELL_SCRIPT_NAME);
+        process
+    } else {
+        Command::new(command)
+    };
+
+    let deadline = options
+        .timeout
+        .and_then(|timeout| Instant::now().checked_add(timeout));
+    let timed_out = || {
+        KakError::Custom(format!(
+            "{command} timed out after {}s",
+            options.timeout.unwrap_or_default().as_secs_f64()
+        ))
+    };
+
+    let mut child = process
+        .args(args)
+        .envs(env.iter().map(|(k, v)| (k, v)))
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
         .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
         .spawn()
-        .expect("Failed to spawn child process");
+        .map_err(|e| {
+            KakError::CustomDetails(format!("Could not run {command}: {e}"), format!("{e:?}"))
+        })?;
+
+    // Pipes are read and written from other threads so a command that writes before it finishes reading cannot block forever
+    // The command may exit while something it started still holds the pipes open, so waiting on them also stops at the deadline
+    let writer = child
+        .stdin
+        .take()
+        .zip(input)
+        .map(|(mut stdin, input)| in_background(move || stdin.write_all(input.as_bytes())));
+    let stdout = child.stdout.take().map(read_in_background);
+    let stderr = child.stderr.take().map(read_in_background);
+
+    let status = wait_until(&mut child, deadline)?.ok_or_else(timed_out)?;
+
+    let read = |reader: Option<Receiver<io::Result<Vec<u8>>>>| -> Result<Vec<u8>, KakError> {
+        match reader {
+            Some(reader) => Ok(receive_until(&reader, deadline)?.ok_or_else(timed_out)??),
+            None => Ok(Vec::new()),
+        }
+    };
+    let stdout = read(stdout)?;
+    let stderr = read(stderr)?;
+
+    if !status.success() && !options.ignore_status {
+        return Err(KakError::CustomDetails(
+            format!("{command} failed ({status})"),
+            String::from_utf8_lossy(&stderr).into_owned(),
+        ));
+    }
 
-    let mut child_stdin = child.stdin.take().expect("Failed to open stdin");
-    let handle = std::thread::spawn(move || -> Result<(), KakError> {
-        for s in get_selections_with_desc(None)? {
-            write!(child_stdin, "{}\0", s.content)?;
+    if let Some(writer) = writer {
+        match receive_until(&writer, deadline)?.ok_or_else(timed_out)? {
+            // The command does not have to read all of its input
+            Err(e) if e.kind() != io::ErrorKind::BrokenPipe => return Err(e.into()),
+            _ => {}
         }
-        Ok(())
+    }
+
+    Ok(stdout)
+}
+
+/// Waits for the child to exit, killing it if it runs past `deadline`
+///
+/// Returns `None` if the child was killed
+fn wait_until(
+    child: &mut Child,
+    deadline: Option<Instant>,
+) -> Result<Option<ExitStatus>, io::Error> {
+    match deadline {
+        None => child.wait().map(Some),
+        Some(deadline) => loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(Some(status));
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                child.kill()?;
+                child.wait()?;
+                return Ok(None);
+            }
+
+            thread::sleep(POLL_INTERVAL.min(remaining));
+        },
+    }
+}
+
+/// Runs `f` on another thread, sending its result to the returned channel
+///
+/// The thread is never joined, so a result that never comes cannot block past a deadline
+fn in_background<T, F>(f: F) -> Receiver<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        // The receiver is gone if the command timed out, so nobody needs the result
+        let _ = sender.send(f());
     });
+    receiver
+}
 
-    let set_selections_result = set_selections_failable(
-        BufReader::new(child.stdout.take().expect("Failed to get stdout"))
-            .split(b'\0')
-            // TODO: Support non-utf8?
-            .map(|s| -> Result<_, KakError> { Ok(String::from_utf8(s?)?) }),
+/// Reads everything from a pipe on another thread
+fn read_in_background<R>(mut pipe: R) -> Receiver<io::Result<Vec<u8>>>
+where
+    R: Read + Send + 'static,
+{
+    in_background(move || {
+        let mut buf = Vec::new();
+        pipe.read_to_end(&mut buf)?;
+        Ok(buf)
+    })
+}
+
+/// Waits for the result of a background thread, returning `None` if `deadline` passes first
+fn receive_until<T>(
+    receiver: &Receiver<T>,
+    deadline: Option<Instant>,
+) -> Result<Option<T>, KakError> {
+    let result = deadline.map_or_else(
+        || receiver.recv().map_err(|_e| RecvTimeoutError::Disconnected),
+        |deadline| receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())),
     );
 
-    // Wait for the background process to exit
-    // Return its error (if there is one) first
-    handle
-        .join()
-        .map_err(|_e| KakError::Custom("Could not join background process".to_string()))??;
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(RecvTimeoutError::Timeout) => Ok(None),
+        Err(RecvTimeoutError::Disconnected) => Err(KakError::Custom(
+            "Could not join background process".to_string(),
+        )),
+    }
+}
+
+/// Parses a timeout in seconds, like `5` or `0.5`
+fn parse_timeout(s: &str) -> Result<Duration, String> {
+    s.parse::<f64>()
+        .ok()
+        .filter(|secs| *secs > 0.0)
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .ok_or_else(|| format!("Invalid timeout '{s}': expected a positive number of seconds"))
+}
+
+/// Parses a delimiter, replacing escapes like `\\0` and `\\n` with the character they stand for
+fn parse_delimiter(s: &str) -> Result<String, String> {
+    let mut ret = String::with_capacity(s.len());
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            ret.push(c);
+            continue;
+        }
+
+        ret.push(match chars.next() {
+            Some('0') => '\0',
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('\\') => '\\',
+            Some(c) => return Err(format!("Unknown escape '\\{c}'")),
+            None => return Err(String::from("Delimiter cannot end with '\\'")),
+        });
+    }
+
+    if ret.is_empty() {
+        return Err(String::from("Delimiter cannot be empty"));
+    }
+
+    Ok(ret)
+}
+
+/// Splits the command's output into one record per selection
+///
+/// The last record does not need to be followed by a delimiter, so `a\0b` and `a\0b\0` are both two records
+fn split_records<'a>(output: &'a [u8], delimiter: &[u8]) -> Vec<&'a [u8]> {
+    let mut records = Vec::new();
+    let mut rest = output;
+
+    while let Some(i) = rest
+        .windows(delimiter.len())
+        .position(|window| window == delimiter)
+    {
+        records.push(&rest[..i]);
+        rest = &rest[i + delimiter.len()..];
+    }
+
+    if !rest.is_empty() {
+        records.push(rest);
+    }
+
+    records
+}
+
+/// Returns an error describing the first selection without a record, or the first record without a selection
+fn check_record_count(
+    records: &[&[u8]],
+    selections: &[SelectionWithDesc],
+    command: &str,
+) -> Result<(), KakError> {
+    let counts = format!(
+        "{command} wrote {} records for {} selections",
+        records.len(),
+        selections.len()
+    );
+
+    match records.len().cmp(&selections.len()) {
+        CmpOrdering::Equal => Ok(()),
+        CmpOrdering::Less => {
+            let missing = &selections[records.len()];
+            Err(KakError::Custom(format!(
+                "{counts}. Record {} is missing, for selection {}: '{}'",
+                records.len() + 1,
+                missing.desc,
+                missing.content
+            )))
+        }
+        CmpOrdering::Greater => Err(KakError::Custom(format!(
+            "{counts}. Record {} has no selection: '{}'",
+            selections.len() + 1,
+            String::from_utf8_lossy(records[selections.len()])
+        ))),
+    }
+}
+
+/// Runs the command once for each selection, at most `--jobs` at a time, replacing each selection with its output
+fn xargs_each(
+    options: &Options,
+    command: &str,
+    args: &[String],
+    selections: &[SelectionWithDesc],
+    metadata: &Metadata,
+) -> Result<String, KakError> {
+    let jobs = options
+        .jobs
+        .map_or_else(
+            || thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            usize::from,
+        )
+        .min(selections.len());
+
+    // Each worker takes the next selection that has not been started yet
+    let next = AtomicUsize::new(0);
+    let mut outputs = thread::scope(|scope| {
+        let workers = (0..jobs)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut outputs = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        match selections.get(i) {
+                            Some(s) => {
+                                outputs.push((
+                                    i,
+                                    run_for_selection(options, command, args, i, s, metadata),
+                                ));
+                            }
+                            None => return outputs,
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        workers
+            .into_iter()
+            .map(thread::ScopedJoinHandle::join)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_e| KakError::Custom("Could not join background process".to_string()))
+    })?
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>();
+
+    // Put the outputs back in the order of the selections
+    outputs.sort_by_key(|(i, _)| *i);
+    let new_selections = outputs
+        .into_iter()
+        .map(|(_, output)| output)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let num_set = set_selections(new_selections.iter())?;
+
+    Ok(format!("Set {num_set} selections from {command}"))
+}
+
+/// Runs the command for one selection and returns its output
+///
+/// A single trailing newline is removed from the output, unless the selection also ended with one
+fn run_for_selection(
+    options: &Options,
+    command: &str,
+    args: &[String],
+    i: usize,
+    selection: &SelectionWithDesc,
+    metadata: &Metadata,
+) -> Result<String, KakError> {
+    let content = &selection.content;
+    // Substituting into a script would let the selection run arbitrary commands
+    let substitute = !options.shell && args.iter().any(|a| a.contains(PLACEHOLDER));
+
+    let input = if options.json {
+        Some(selection_json(i, selection, metadata) + "\n")
+    } else if substitute {
+        None
+    } else {
+        Some(content.clone())
+    };
+
+    let mut env = metadata.env();
+    env.push((String::from("kak_selection_index"), (i + 1).to_string()));
+    env.push((
+        String::from("kak_selection_desc"),
+        selection.desc.to_string(),
+    ));
+
+    let args = if options.shell {
+        iter::once(content.clone())
+            .chain(args.iter().cloned())
+            .collect::<Vec<_>>()
+    } else {
+        args.iter()
+            .map(|a| a.replace(PLACEHOLDER, content))
+            .collect()
+    };
+
+    let output = run_command(options, command, args, input, &env)?;
+
+    let mut stdout = String::from_utf8(output)?;
+    if stdout.ends_with('\n') && !content.ends_with('\n') {
+        stdout.pop();
+    }
+
+    Ok(stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kakplugin::types::SelectionDesc;
+
+    #[test]
+    fn test_parse_options() {
+        #[derive(clap::Parser)]
+        struct Cli {
+            #[clap(flatten)]
+            options: Options,
+        }
+        let parse = |args: &[&str]| {
+            <Cli as clap::Parser>::try_parse_from(iter::once("xargs").chain(args.iter().copied()))
+                .map(|cli| cli.options)
+        };
+
+        // Flags after the command belong to the command, even if xargs has a flag with the same name
+        let o = parse(&["sed", "-e", "s/a/b/"]).unwrap();
+        assert!(!o.each);
+        assert_eq!(o.argv, ["sed", "-e", "s/a/b/"]);
+
+        let o = parse(&["tr", "-d", "a"]).unwrap();
+        assert_eq!(o.delimiter, "\0");
+        assert_eq!(o.argv, ["tr", "-d", "a"]);
+
+        let o = parse(&["-e", "-c", "grep -c x", "-r"]).unwrap();
+        assert!(o.each && o.shell);
+        assert!(o.register.is_empty());
+        assert_eq!(o.argv, ["grep -c x", "-r"]);
+
+        assert_eq!(parse(&["sort", "-r"]).unwrap().argv, ["sort", "-r"]);
+        assert!(parse(&["-e"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_delimiter() {
+        assert_eq!(parse_delimiter("\\0").as_deref(), Ok("\0"));
+        assert_eq!(parse_delimiter("\\n").as_deref(), Ok("\n"));
+        assert_eq!(parse_delimiter(",\\t").as_deref(), Ok(",\t"));
+        assert_eq!(parse_delimiter("\\\\").as_deref(), Ok("\\"));
+        assert_eq!(parse_delimiter("--").as_deref(), Ok("--"));
+        assert!(parse_delimiter("").is_err());
+        assert!(parse_delimiter("\\").is_err());
+        assert!(parse_delimiter("\\q").is_err());
+    }
+
+    #[test]
+    fn test_parse_timeout() {
+        assert_eq!(parse_timeout("5"), Ok(Duration::from_secs(5)));
+        assert_eq!(parse_timeout("0.25"), Ok(Duration::from_millis(250)));
+        assert!(parse_timeout("0").is_err());
+        assert!(parse_timeout("-1").is_err());
+        assert!(parse_timeout("inf").is_err());
+        assert!(parse_timeout("soon").is_err());
+    }
+
+    #[test]
+    fn test_metadata() {
+        let metadata = Metadata {
+            bufname: String::from("notes.txt"),
+            selection_count: 2,
+            registers: vec![
+                (
+                    Register::LowercaseA,
+                    vec![String::from("it's"), String::from("b")],
+                ),
+                (Register::Dquote, Vec::new()),
+            ],
+        };
+        let selection = SelectionWithDesc {
+            content: String::from("a \"b\""),
+            desc: SelectionDesc {
+                left: AnchorPosition { row: 3, col: 7 },
+                right: AnchorPosition { row: 3, col: 1 },
+            },
+        };
 
-    // Now print any errors
-    let num_set = set_selections_result?;
+        assert_eq!(
+            metadata.env(),
+            [
+                ("kak_bufname", "notes.txt"),
+                ("kak_selection_count", "2"),
+                ("kak_reg_a", "'it'\\''s' b"),
+                ("kak_reg_dquote", ""),
+            ]
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+        );
+        assert_eq!(
+            selection_json(1, &selection, &metadata),
+            r#"{"anchor":{"col":7,"row":3},"bufname":"notes.txt","content":"a \"b\"","cursor":{"col":1,"row":3},"desc":"3.7,3.1","index":2,"registers":{"a":["it's","b"],"dquote":[]}}"#
+        );
+    }
 
-    Ok(format!(
-        "Set {} selections from {}",
-        num_set, options.command
-    ))
+    #[test]
+    fn test_split_records() {
+        assert_eq!(split_records(b"a\0b", b"\0"), vec![&b"a"[..], b"b"]);
+        assert_eq!(split_records(b"a\0b\0", b"\0"), vec![&b"a"[..], b"b"]);
+        assert_eq!(split_records(b"a\0\0b", b"\0"), vec![&b"a"[..], b"", b"b"]);
+        assert_eq!(split_records(b"a--b--", b"--"), vec![&b"a"[..], b"b"]);
+        assert_eq!(split_records(b"a-b", b"--"), vec![&b"a-b"[..]]);
+        assert!(split_records(b"", b"\n").is_empty());
+    }
 }
@@ -1,56 +1,203 @@
-use crate::utils::get_hash;
+use crate::utils::{get_hash, KeyOptions};
 use kakplugin::{get_selections, set_selections, types::Register, KakError, Selection};
+use serde::{
+    de::{MapAccess, Visitor},
+    Deserialize, Deserializer,
+};
+use serde_json::Value;
 use std::{
     borrow::Cow,
     collections::{
         btree_map::Entry::{Occupied, Vacant},
         BTreeMap,
     },
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
 };
 
 #[derive(clap::Args, Debug)]
 pub struct Options {
     #[clap(help = "Register with the lookup table", default_value = "^")]
     register: Register,
+    #[clap(flatten)]
+    table: TableOptions,
+    #[clap(flatten)]
+    key: KeyOptions,
+    #[clap(
+        long,
+        conflicts_with = "keep_on_miss",
+        help = "Replace selections that are not in the lookup table with this instead of clearing them"
+    )]
+    default: Option<String>,
+    #[clap(
+        long,
+        help = "Leave selections that are not in the lookup table unchanged"
+    )]
+    keep_on_miss: bool,
+}
+
+/// Where to find the lookup table when it is in a file
+#[derive(clap::Args, Debug, Default)]
+struct TableOptions {
+    #[clap(
+        short,
+        long,
+        value_name = "PATH",
+        conflicts_with = "register",
+        help = "Read the lookup table from a CSV, TSV, JSON, or JSON lines file instead of a register"
+    )]
+    file: Option<PathBuf>,
+    #[clap(
+        long,
+        value_enum,
+        requires = "file",
+        help = "Format of the file (default: guessed from its extension)"
+    )]
+    format: Option<TableFormat>,
+    #[clap(
+        long,
+        default_value = "1",
+        value_name = "COLUMN",
+        requires = "file",
+        help = "Column with the keys, as a number (starting at 1) or a header name"
+    )]
+    key_column: Column,
+    #[clap(
+        long,
+        default_value = "2",
+        value_name = "COLUMN",
+        requires = "file",
+        help = "Column with the values, as a number (starting at 1) or a header name"
+    )]
+    value_column: Column,
+    #[clap(
+        long,
+        requires = "file",
+        help = "Skip the first row of a CSV or TSV file. Implied when a column is a header name"
+    )]
+    header: bool,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum TableFormat {
+    Csv,
+    Tsv,
+    /// An object of keys to values, or an array of rows
+    Json,
+    /// One row per line
+    Jsonl,
+}
+
+impl TableFormat {
+    /// Guesses the format of a file from its extension
+    fn for_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_lowercase().as_str() {
+            "csv" => Some(Self::Csv),
+            "tsv" | "tab" => Some(Self::Tsv),
+            "json" => Some(Self::Json),
+            "jsonl" | "ndjson" => Some(Self::Jsonl),
+            _ => None,
+        }
+    }
+}
+
+/// A column in a lookup table file, either by position or by header name
+///
+/// JSON rows that are arrays use positions, and rows that are objects use names
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Column {
+    /// Index starting at 0
+    Index(usize),
+    Name(String),
+}
+
+impl Default for Column {
+    fn default() -> Self {
+        Self::Index(0)
+    }
+}
+
+impl FromStr for Column {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse::<usize>() {
+            Ok(0) => Err(String::from("Columns start at 1")),
+            Ok(n) => Ok(Self::Index(n - 1)),
+            Err(_) if s.is_empty() => Err(String::from("Column cannot be empty")),
+            Err(_) => Ok(Self::Name(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Column {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Index(i) => write!(f, "{}", i + 1),
+            Self::Name(name) => write!(f, "'{name}'"),
+        }
+    }
 }
+
 pub fn xlookup(options: &Options) -> Result<String, KakError> {
-    eprintln!("Getting registers for {options:?}");
-    let lookup_table = build_lookuptable(kakplugin::get_register_selections(options.register)?)?;
+    let lookup_table = if let Some(path) = &options.table.file {
+        build_lookuptable_from_pairs(read_table_file(path, &options.table)?, &options.key)?
+    } else {
+        build_lookuptable(
+            kakplugin::get_register_selections(options.register)?,
+            &options.key,
+        )?
+    };
     // let lookup_table = build_lookuptable(kakplugin::reg(options.register, None)?)?;
 
     let selections = get_selections(None)?;
 
-    let mut err_count: usize = 0;
+    let mut not_found = Vec::new();
 
     set_selections(selections.iter().map(|key| {
-        lookup_table
-            .get(&get_hash(&key, false, None, false))
-            .map_or_else(
-                || {
-                    eprintln!("Key '{key}' not found",);
-                    err_count += 1;
-                    Cow::Borrowed("")
-                },
-                |s| Cow::Owned(ToString::to_string(s)),
-            )
+        lookup_table.get(&get_hash(key, &options.key)).map_or_else(
+            || {
+                not_found.push(key);
+                if options.keep_on_miss {
+                    Cow::Borrowed(key.as_str())
+                } else {
+                    Cow::Borrowed(options.default.as_deref().unwrap_or_default())
+                }
+            },
+            |s| Cow::Owned(ToString::to_string(s)),
+        )
     }))?;
 
-    Ok(if err_count == 0 {
-        format!("Xlookup {} selections", selections.len())
-    } else {
-        format!(
-            "Xlookup {} selections ({} error{})",
-            selections.len().saturating_sub(err_count),
-            err_count,
-            if err_count == 1 { "" } else { "s" }
-        )
-    })
+    if not_found.is_empty() {
+        return Ok(format!("Xlookup {} selections", selections.len()));
+    }
+
+    kakplugin::echo_debug(format!(
+        "{} key{} not found in the lookup table:\n{}",
+        not_found.len(),
+        if not_found.len() == 1 { "" } else { "s" },
+        not_found
+            .iter()
+            .map(|key| format!("'{key}'"))
+            .collect::<Vec<_>>()
+            .join("\n")
+    ))?;
+
+    Ok(format!(
+        "Xlookup {} selections ({} not found, see *debug*)",
+        selections.len().saturating_sub(not_found.len()),
+        not_found.len()
+    ))
 }
 
-fn build_lookuptable(mut selections: Vec<Selection>) -> Result<BTreeMap<u64, Selection>, KakError> {
+fn build_lookuptable(
+    mut selections: Vec<Selection>,
+    key_options: &KeyOptions,
+) -> Result<BTreeMap<u64, Selection>, KakError> {
     let mut iter = selections.array_chunks_mut();
     let ret = iter.try_fold(BTreeMap::new(), |mut acc, [key, value]| {
-        match acc.entry(get_hash(&key, false, None, false)) {
+        match acc.entry(get_hash(key, key_options)) {
             Occupied(_) => Err(KakError::Custom(format!("Duplicate key '{key}'"))),
             Vacant(v) => {
                 v.insert(value.clone());
@@ -68,17 +215,224 @@ fn build_lookuptable(mut selections: Vec<Selection>) -> Result<BTreeMap<u64, Sel
     }
 }
 
+fn build_lookuptable_from_pairs(
+    pairs: Vec<(String, String)>,
+    key_options: &KeyOptions,
+) -> Result<BTreeMap<u64, Selection>, KakError> {
+    let ret = pairs
+        .into_iter()
+        .try_fold(BTreeMap::new(), |mut acc, (key, value)| {
+            match acc.entry(get_hash(&key, key_options)) {
+                Occupied(_) => Err(KakError::Custom(format!("Duplicate key '{key}'"))),
+                Vacant(v) => {
+                    v.insert(value);
+                    Ok(acc)
+                }
+            }
+        })?;
+
+    if ret.is_empty() {
+        Err(KakError::CustomStatic("Lookup table file is empty"))
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Reads the key and value of each row in a lookup table file
+fn read_table_file(path: &Path, table: &TableOptions) -> Result<Vec<(String, String)>, KakError> {
+    let format = table
+        .format
+        .or_else(|| TableFormat::for_path(path))
+        .ok_or_else(|| {
+            KakError::Custom(format!(
+                "Could not guess the format of {} (use --format)",
+                path.display()
+            ))
+        })?;
+
+    let contents = fs::read_to_string(path).map_err(|e| {
+        KakError::CustomDetails(
+            format!("Could not read {}: {e}", path.display()),
+            format!("{e:?}"),
+        )
+    })?;
+
+    match format {
+        TableFormat::Csv => delimited_pairs(&contents, b',', table),
+        TableFormat::Tsv => delimited_pairs(&contents, b'\t', table),
+        TableFormat::Json => json_pairs(&contents, table),
+        TableFormat::Jsonl => jsonl_pairs(&contents, table),
+    }
+}
+
+fn delimited_pairs(
+    contents: &str,
+    delimiter: u8,
+    table: &TableOptions,
+) -> Result<Vec<(String, String)>, KakError> {
+    let csv_error = |e: csv::Error| KakError::Custom(format!("Could not parse lookup table: {e}"));
+
+    let has_headers = table.header
+        || matches!(table.key_column, Column::Name(_))
+        || matches!(table.value_column, Column::Name(_));
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .has_headers(has_headers)
+        // TSV files do not quote fields, so quotes are part of the value
+        .quoting(delimiter != b'\t')
+        .flexible(true)
+        .from_reader(contents.as_bytes());
+
+    let headers = if has_headers {
+        Some(reader.headers().map_err(csv_error)?.clone())
+    } else {
+        None
+    };
+    let column_index = |column: &Column| match column {
+        Column::Index(i) => Ok(*i),
+        Column::Name(name) => headers
+            .as_ref()
+            .and_then(|h| h.iter().position(|h| h == name))
+            .ok_or_else(|| KakError::Custom(format!("No column named '{name}'"))),
+    };
+    let (key, value) = (
+        column_index(&table.key_column)?,
+        column_index(&table.value_column)?,
+    );
+
+    reader
+        .records()
+        .map(|record| {
+            let record = record.map_err(csv_error)?;
+            let field = |i: usize| {
+                record.get(i).map(str::to_string).ok_or_else(|| {
+                    KakError::Custom(format!(
+                        "Line {} has no column {}",
+                        record.position().map_or(0, csv::Position::line),
+                        i + 1
+                    ))
+                })
+            };
+            Ok((field(key)?, field(value)?))
+        })
+        .collect()
+}
+
+fn json_pairs(contents: &str, table: &TableOptions) -> Result<Vec<(String, String)>, KakError> {
+    let json_error =
+        |e: serde_json::Error| KakError::Custom(format!("Could not parse lookup table: {e}"));
+
+    // Objects are read as entries instead of a map so duplicate keys are not silently dropped
+    if contents.trim_start().starts_with('{') {
+        let Entries(entries) = serde_json::from_str(contents).map_err(json_error)?;
+        return Ok(entries
+            .into_iter()
+            .map(|(key, value)| (key, json_text(&value)))
+            .collect());
+    }
+
+    match serde_json::from_str(contents).map_err(json_error)? {
+        Value::Array(rows) => rows
+            .iter()
+            .enumerate()
+            .map(|(i, row)| {
+                json_row(row, table).map_err(|e| KakError::Custom(format!("Row {} {e}", i + 1)))
+            })
+            .collect(),
+        _ => Err(KakError::CustomStatic(
+            "JSON lookup table must be an object or an array of rows",
+        )),
+    }
+}
+
+fn jsonl_pairs(contents: &str, table: &TableOptions) -> Result<Vec<(String, String)>, KakError> {
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line)
+                .map_err(|e| format!("could not be parsed: {e}"))
+                .and_then(|row| json_row(&row, table))
+                .map_err(|e| KakError::Custom(format!("Line {} {e}", i + 1)))
+        })
+        .collect()
+}
+
+/// Gets the key and value out of a JSON row, which is an array or an object
+fn json_row(row: &Value, table: &TableOptions) -> Result<(String, String), String> {
+    let field = |column: &Column| {
+        match (row, column) {
+            (Value::Array(row), Column::Index(i)) => row.get(*i),
+            (Value::Object(row), Column::Name(name)) => row.get(name),
+            (Value::Array(_), Column::Name(_)) => {
+                return Err(String::from("is an array, so its columns must be numbers"))
+            }
+            (Value::Object(_), Column::Index(_)) => return Err(String::from(
+                "is an object, so its columns must be names (use --key-column and --value-column)",
+            )),
+            _ => return Err(String::from("is not an array or an object")),
+        }
+        .map(json_text)
+        .ok_or_else(|| format!("has no column {column}"))
+    };
+
+    Ok((field(&table.key_column)?, field(&table.value_column)?))
+}
+
+/// Gets the text of a JSON value, without quotes if it is a string
+fn json_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        _ => value.to_string(),
+    }
+}
+
+/// The entries of a JSON object in file order, including duplicate keys
+struct Entries(Vec<(String, Value)>);
+
+impl<'de> Deserialize<'de> for Entries {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct EntriesVisitor;
+
+        impl<'de> Visitor<'de> for EntriesVisitor {
+            type Value = Entries;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a JSON object")
+            }
+
+            fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+            where
+                A: MapAccess<'de>,
+            {
+                let mut entries = Vec::new();
+                while let Some(entry) = map.next_entry()? {
+                    entries.push(entry);
+                }
+                Ok(Entries(entries))
+            }
+        }
+
+        deserializer.deserialize_map(EntriesVisitor)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     macro_rules! blt {
         ($($x:expr),+ $(,)?) => {
-            build_lookuptable(vec![$($x.to_string()),+])
+            build_lookuptable(vec![$($x.to_string()),+], &KeyOptions::default())
         }
     }
     macro_rules! hsh {
         ($expr:expr) => {
-            get_hash($expr, false, None, false)
+            get_hash($expr, &KeyOptions::default())
         };
     }
     #[test]
@@ -94,5 +448,94 @@ mod tests {
         assert_eq!(lt.get(&hsh!("1")), Some(&String::from("b")));
         assert_eq!(lt.get(&hsh!("2")), Some(&String::from("c")));
         assert_eq!(lt.get(&hsh!("3")), None);
+
+        // Table keys and lookup keys are normalized the same way
+        let o = KeyOptions {
+            ignore_case: true,
+            ..KeyOptions::default()
+        };
+        let lt =
+            build_lookuptable(vec![String::from("Host-A "), String::from("10.0.0.1")], &o).unwrap();
+        assert_eq!(
+            lt.get(&get_hash("  host-a\n", &o)),
+            Some(&String::from("10.0.0.1"))
+        );
+        assert_eq!(lt.get(&hsh!("Host-A")), None);
+
+        // Keys that are only different before normalization are duplicates
+        assert!(build_lookuptable(
+            vec![
+                String::from("A"),
+                String::from("1"),
+                String::from("a"),
+                String::from("2")
+            ],
+            &o
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_table_file() {
+        let columns = |key: &str, value: &str| TableOptions {
+            key_column: key.parse().unwrap(),
+            value_column: value.parse().unwrap(),
+            ..TableOptions::default()
+        };
+        let pairs = |v: &[(&str, &str)]| {
+            v.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            "0".parse::<Column>(),
+            Err(String::from("Columns start at 1"))
+        );
+        assert_eq!(
+            TableFormat::for_path(Path::new("hosts.JSONL")),
+            Some(TableFormat::Jsonl)
+        );
+
+        // Named columns use the header, in any order
+        assert_eq!(
+            delimited_pairs(
+                "ip,host\n10.0.0.1,\"a, b\"\n10.0.0.2,c\n",
+                b',',
+                &columns("host", "ip")
+            )
+            .unwrap(),
+            pairs(&[("a, b", "10.0.0.1"), ("c", "10.0.0.2")])
+        );
+        assert_eq!(
+            delimited_pairs("a\t\"1\"\nb\t2", b'\t', &columns("1", "2")).unwrap(),
+            pairs(&[("a", "\"1\""), ("b", "2")])
+        );
+        assert!(delimited_pairs("a,1\nb\n", b',', &columns("1", "2")).is_err());
+        assert!(delimited_pairs("host,ip\n", b',', &columns("name", "ip")).is_err());
+
+        assert_eq!(
+            json_pairs(r#"{"a": "x", "b": 2, "c": null}"#, &columns("1", "2")).unwrap(),
+            pairs(&[("a", "x"), ("b", "2"), ("c", "")])
+        );
+        assert_eq!(
+            json_pairs(r#"[["a", 1], ["b", 2]]"#, &columns("1", "2")).unwrap(),
+            pairs(&[("a", "1"), ("b", "2")])
+        );
+        assert_eq!(
+            jsonl_pairs(
+                "{\"host\": \"a\", \"ip\": \"10.0.0.1\"}\n\n{\"host\": \"b\", \"ip\": \"10.0.0.2\"}\n",
+                &columns("host", "ip")
+            )
+            .unwrap(),
+            pairs(&[("a", "10.0.0.1"), ("b", "10.0.0.2")])
+        );
+        assert!(jsonl_pairs("{\"host\": \"a\"}", &columns("1", "2")).is_err());
+        assert!(jsonl_pairs("[\"a\"]", &columns("1", "2")).is_err());
+
+        // Duplicate keys in a JSON object are kept so they can be reported
+        let duplicates = json_pairs(r#"{"a": 1, "a": 2}"#, &columns("1", "2")).unwrap();
+        assert_eq!(duplicates.len(), 2);
+        assert!(build_lookuptable_from_pairs(duplicates, &KeyOptions::default()).is_err());
     }
 }